@@ -0,0 +1,170 @@
+//! An optional pass that looks for arguments of the wrong kind before a
+//! program runs. It only reports what would definitely go wrong: what a
+//! variable holds is followed through straight-line code, but forgotten
+//! wherever a jump or callback could have changed it.
+
+use std::collections::{HashMap, HashSet};
+
+use macaroni::{Kind, MacaroniError, OpInfo, Val};
+use parser::{Node, NodeKind};
+
+struct Checker<'a, F: Fn(&str) -> Option<&'a OpInfo>> {
+    op: F,
+    /// What each variable is known to hold; unset ones are 0.
+    vars: HashMap<String, Kind>,
+    /// Every variable that's `set` somewhere, plus `_`.
+    assigned: HashSet<String>,
+    errors: Vec<MacaroniError>
+}
+
+impl<'a, F: Fn(&str) -> Option<&'a OpInfo>> Checker<'a, F> {
+    /// Returns what `node` evaluates to: `Num`, `Arr`, `Any` if it could be
+    /// either, or `Nothing`.
+    fn expr(&mut self, node: &Node) -> Kind {
+        match node.kind {
+            NodeKind::Literal(ref val) => kind_of(val),
+            NodeKind::VarRef(ref name) =>
+                self.vars.get(name).cloned().unwrap_or(Kind::Num),
+            NodeKind::Call { ref op, ref args } => {
+                let info = (self.op)(op).expect("parsed with unknown operator");
+                let mut kinds = Vec::with_capacity(args.len());
+                for (arg, &want) in args.iter().zip(&info.args) {
+                    let got = self.expr(arg);
+                    let bad = match want {
+                        // `set` gives back the variable it set, name and all
+                        Kind::Label | Kind::Var => match arg.kind {
+                            NodeKind::VarRef(_) => false,
+                            NodeKind::Call { ref op, .. } => op != "set",
+                            _ => true
+                        },
+                        Kind::Num => got == Kind::Arr || got == Kind::Nothing,
+                        Kind::Arr => got == Kind::Num || got == Kind::Nothing,
+                        Kind::Any | Kind::Nothing => got == Kind::Nothing
+                    };
+                    if bad {
+                        self.errors.push(MacaroniError::KindMismatch {
+                            op: op.clone(), want, got, span: arg.span
+                        });
+                    }
+                    kinds.push(got);
+                }
+                match &op[..] {
+                    "set" => {
+                        if let NodeKind::VarRef(ref name) = args[0].kind {
+                            self.vars.insert(name.clone(), kinds[1]);
+                        }
+                        return kinds[1];
+                    },
+                    // whatever comes next is only reached by jumping there
+                    "return" => self.forget(),
+                    _ => if info.args.contains(&Kind::Label) { self.forget(); }
+                }
+                info.ret
+            },
+            NodeKind::Label(_) => { self.forget(); Kind::Nothing }
+        }
+    }
+
+    fn forget(&mut self) {
+        for name in &self.assigned {
+            self.vars.insert(name.clone(), Kind::Any);
+        }
+    }
+}
+
+/// Finds every variable that a `set` in `node` could change.
+pub(crate) fn find_assigned<'a>(node: &'a Node,
+                                assigned: &mut HashSet<&'a str>) {
+    if let NodeKind::Call { ref op, ref args } = node.kind {
+        if let (true, Some(NodeKind::VarRef(name))) =
+                (op == "set", args.first().map(|a| &a.kind)) {
+            assigned.insert(name);
+        }
+        for arg in args { find_assigned(arg, assigned); }
+    }
+}
+
+fn kind_of(val: &Val) -> Kind {
+    match *val {
+        Val::Num(_) => Kind::Num,
+        Val::Arr(_) => Kind::Arr
+    }
+}
+
+/// Checks `program`, which was parsed with the operators `op` describes,
+/// as if it were about to run with variables `vars` after `earlier` code
+/// (whose labels it might jump to). Returns every mismatch found, in order.
+pub fn check<'a, F>(program: &[Node], earlier: &[Node], op: F,
+                    vars: &HashMap<String, Val>) -> Vec<MacaroniError>
+        where F: Fn(&str) -> Option<&'a OpInfo> {
+    infer(program, earlier, op, vars).1
+}
+
+/// Like `check`, but also works out what the last statement evaluates to
+/// (`Any` if it could be either a number or an array).
+pub fn infer<'a, F>(program: &[Node], earlier: &[Node], op: F,
+                    vars: &HashMap<String, Val>) -> (Kind, Vec<MacaroniError>)
+        where F: Fn(&str) -> Option<&'a OpInfo> {
+    let mut assigned = HashSet::new();
+    assigned.insert("_");
+    for node in program.iter().chain(earlier) {
+        find_assigned(node, &mut assigned);
+    }
+    let mut checker = Checker {
+        op,
+        vars: vars.iter().map(|(name, val)| (name.clone(), kind_of(val)))
+            .collect(),
+        assigned: assigned.into_iter().map(str::to_string).collect(),
+        errors: vec![]
+    };
+    let mut last = Kind::Nothing;
+    for node in program {
+        let kind = checker.expr(node);
+        // a label doesn't change what the program evaluates to
+        if let NodeKind::Call { .. } | NodeKind::Literal(_) |
+                NodeKind::VarRef(_) = node.kind {
+            last = kind;
+        }
+    }
+    (last, checker.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use macaroni::{Kind, Macaroni, MacaroniError};
+
+    /// `(op, want, got)` for each mismatch in `src`.
+    fn mismatches(src: &str) -> Vec<(String, Kind, Kind)> {
+        Macaroni::new().check(src).unwrap().into_iter().map(|e| match e {
+            MacaroniError::KindMismatch { op, want, got, .. } =>
+                (op, want, got),
+            e => panic!("unexpected {}", e)
+        }).collect()
+    }
+
+    #[test]
+    fn finds_mismatches() {
+        assert_eq!(mismatches("length 1"),
+                   [("length".to_string(), Kind::Arr, Kind::Num)]);
+        assert_eq!(mismatches("set x [1] add x 1"),
+                   [("add".to_string(), Kind::Num, Kind::Arr)]);
+        assert_eq!(mismatches("print print \"hi\""),
+                   [("print".to_string(), Kind::Arr, Kind::Nothing)]);
+        assert_eq!(mismatches("set 1 2"),
+                   [("set".to_string(), Kind::Var, Kind::Num)]);
+        assert_eq!(mismatches("set add 1 2 3"),
+                   [("set".to_string(), Kind::Var, Kind::Num)]);
+    }
+
+    #[test]
+    fn only_what_definitely_fails() {
+        assert!(mismatches("set x 1 add x 1 set x [1] length x").is_empty());
+        // `set` gives back something with a name
+        assert!(mismatches("set set w 1 2").is_empty());
+        // a jump might have changed what `x` holds
+        assert!(mismatches("set x 1 goto f length x return \
+                            label f set x [1] return").is_empty());
+        assert!(mismatches("set x 1 map [1] f length x return \
+                            label f set x [1] set _ 0 return").is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! `--debug` mode: stops before operators and at breakpoints, and lets you
+//! look around at the interpreter's state.
+
+use std::collections::BTreeSet;
+use std::process;
+
+use macaroni_lang::macaroni::{self, Arg, Hook, Macaroni, Span, Val};
+use rustyline::DefaultEditor;
+
+const HELP: &str = "\
+s, step          run until the next operator
+c, continue      run until the next breakpoint
+b <line|label>   break at a line or label
+d <line|label>   delete a breakpoint
+v, vars          show variables
+st, states       show running states and their pending gotos
+bt, backtrace    show every goto still waiting for a return
+l, list          show where the program is
+q, quit          stop the program
+(an empty line repeats `step`)";
+
+pub struct Debugger {
+    name: String,
+    source: String,
+    rl: DefaultEditor,
+    stepping: bool,
+    lines: BTreeSet<usize>,
+    labels: BTreeSet<String>,
+    /// Line of the last operator, so a line breakpoint only stops once
+    /// however many operators are on the line; 0 after a jump, so it stops
+    /// again each time the line is jumped back to.
+    last_line: usize,
+    /// Where the program is stopped, and what it's about to do there.
+    at: Span,
+    doing: String
+}
+
+impl Debugger {
+    pub fn new(name: &str, source: &str) -> rustyline::Result<Debugger> {
+        println!("debugging {}; type h for help", name);
+        Ok(Debugger {
+            name: name.to_string(), source: source.to_string(),
+            rl: DefaultEditor::new()?, stepping: true,
+            lines: BTreeSet::new(), labels: BTreeSet::new(),
+            last_line: 0, at: Span::default(), doing: String::new()
+        })
+    }
+
+    fn show(&self) {
+        println!("{}", macaroni::render(self.at, &self.doing, &self.name,
+                                        &self.source));
+    }
+
+    fn show_states(mac: &Macaroni) {
+        for (i, state) in mac.states().iter().enumerate() {
+            match state.label {
+                Some(ref label) => println!("#{} callback at label {}, at {}:{}",
+                    i, label, state.op.line, state.op.col),
+                None => println!("#{} program, at {}:{}",
+                    i, state.op.line, state.op.col)
+            }
+            for goto in &state.gotos {
+                println!("    goto at {}:{}", goto.line, goto.col);
+            }
+        }
+    }
+
+    fn backtrace(mac: &Macaroni) {
+        for (i, state) in mac.states().iter().enumerate() {
+            for goto in state.gotos.iter().rev() {
+                println!("#{} goto at {}:{}", i, goto.line, goto.col);
+            }
+        }
+    }
+
+    fn breakpoint(&mut self, arg: &str, add: bool) {
+        let changed = match arg.parse::<usize>() {
+            Ok(line) if add => self.lines.insert(line),
+            Ok(line) => self.lines.remove(&line),
+            Err(_) if arg.is_empty() => {
+                for line in &self.lines { println!("line {}", line); }
+                for label in &self.labels { println!("label {}", label); }
+                return;
+            },
+            Err(_) if add => self.labels.insert(arg.to_string()),
+            Err(_) => self.labels.remove(arg)
+        };
+        if !changed {
+            println!("{} {}", arg,
+                     if add { "is already a breakpoint" } else { "isn't a breakpoint" });
+        }
+    }
+
+    /// Reads commands until one of them resumes the program.
+    fn prompt(&mut self, mac: &Macaroni) {
+        loop {
+            let line = match self.rl.readline("(debug) ") {
+                Ok(line) => line,
+                Err(_) => process::exit(1)
+            };
+            let _ = self.rl.add_history_entry(&line[..]);
+            let mut words = line.split_whitespace();
+            let (cmd, arg) = (words.next().unwrap_or("s"), words.next().unwrap_or(""));
+            match cmd {
+                "s" | "step" => { self.stepping = true; return; },
+                "c" | "continue" => { self.stepping = false; return; },
+                "b" | "break" => self.breakpoint(arg, true),
+                "d" | "delete" => self.breakpoint(arg, false),
+                "v" | "vars" => {
+                    let vars = mac.vars();
+                    let mut names = vars.keys().collect::<Vec<_>>();
+                    names.sort();
+                    for name in names { println!("{} = {}", name, vars[name]); }
+                },
+                "st" | "states" => Debugger::show_states(mac),
+                "bt" | "backtrace" => Debugger::backtrace(mac),
+                "l" | "list" => self.show(),
+                "q" | "quit" => process::exit(1),
+                "h" | "help" => println!("{}", HELP),
+                _ => println!("unknown command {} (try h)", cmd)
+            }
+        }
+    }
+}
+
+impl Hook for Debugger {
+    fn before_op(&mut self, mac: &Macaroni, op: &str, args: &[Arg], span: Span) {
+        let new_line = span.line != self.last_line;
+        self.last_line = span.line;
+        if self.stepping || (new_line && self.lines.contains(&span.line)) {
+            self.at = span;
+            self.doing = format!("{}{}", op, args.iter()
+                .map(|a| format!(" {}", a)).collect::<String>());
+            self.show();
+            self.prompt(mac);
+        }
+    }
+
+    fn on_label(&mut self, mac: &Macaroni, name: &str, span: Span) {
+        self.last_line = 0;
+        if !self.stepping && self.labels.contains(name) {
+            self.at = span;
+            self.doing = format!("label {}", name);
+            self.show();
+            self.prompt(mac);
+        }
+    }
+
+    fn on_goto(&mut self, _mac: &Macaroni, _name: &str, _span: Span, _to: Span) {
+        self.last_line = 0;
+    }
+
+    fn on_return(&mut self, _mac: &Macaroni, _span: Span, _to: Option<Span>) {
+        self.last_line = 0;
+    }
+
+    fn on_callback(&mut self, _mac: &Macaroni, _op: &str, _name: &str,
+                   _arg: &Val, _span: Span) {
+        self.last_line = 0;
+    }
+}
@@ -0,0 +1,276 @@
+//! Lays out Macaroni source consistently. Every top-level statement gets a
+//! line of its own, and statements after a `label` are indented under it.
+//! A statement that's too long for one line, or too hard to follow on one
+//! (calls nested deeply, or several calls as one operator's arguments), is
+//! broken up, with each argument on its own line indented under its
+//! operator, and so on down.
+//! Comments stay next to whatever they were next to, and a blank line
+//! between statements is kept (though several become one). Formatting
+//! already formatted code changes nothing.
+
+use lexer::{self, Lexeme, LexemeKind, Span};
+use macaroni::MacaroniError;
+use parser::{self, Node, NodeKind};
+
+const WIDTH: usize = 80;
+const INDENT: usize = 4;
+/// How deeply calls may nest on one line.
+const NESTING: usize = 3;
+
+/// A piece of a statement that's always written as a whole: an operator,
+/// variable, literal or `label name`, with its arguments if it has any.
+struct Item {
+    /// Comments on lines of their own just before the item.
+    leading: Vec<String>,
+    text: String,
+    /// A comment after the item's text, on the same line.
+    trailing: Option<String>,
+    args: Vec<Item>
+}
+
+impl Item {
+    /// The item on one line, if it can go on one. Only the very last thing
+    /// in a statement (when `last` is set) may have a comment after it.
+    fn flat(&self, last: bool) -> Option<String> {
+        let is_last = last && self.args.is_empty();
+        if self.text.contains('\n') || (self.trailing.is_some() && !is_last) {
+            return None;
+        }
+        let calls = self.args.iter().filter(|arg| !arg.args.is_empty()).count();
+        if calls > 1 || self.nesting() > NESTING { return None; }
+        let mut s = self.text.clone();
+        for (i, arg) in self.args.iter().enumerate() {
+            if !arg.leading.is_empty() { return None; }
+            s.push(' ');
+            s.push_str(&arg.flat(last && i == self.args.len() - 1)?);
+        }
+        Some(s)
+    }
+
+    /// How many calls deep the item goes, counting itself.
+    fn nesting(&self) -> usize {
+        if self.args.is_empty() { return 0; }
+        1 + self.args.iter().map(Item::nesting).max().unwrap_or(0)
+    }
+
+    fn last(&self) -> &Item {
+        self.args.last().map_or(self, Item::last)
+    }
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    /// Everything but whitespace and comments, to find labels' names in.
+    tokens: Vec<&'a Lexeme>,
+    comments: Vec<&'a Lexeme>,
+    /// The first comment that hasn't been put anywhere yet.
+    next: usize,
+    out: String
+}
+
+impl<'a> Formatter<'a> {
+    fn text(&self, span: Span) -> &'a str {
+        &self.source[span.offset..span.offset + span.len]
+    }
+
+    /// Takes every comment that starts before `offset`.
+    fn comments_before(&mut self, offset: usize) -> Vec<String> {
+        let mut comments = vec![];
+        while self.comments.get(self.next)
+                .is_some_and(|c| c.span.offset < offset) {
+            comments.push(self.text(self.comments[self.next].span).to_string());
+            self.next += 1;
+        }
+        comments
+    }
+
+    fn item(&mut self, node: &Node) -> Item {
+        let start = node.span.offset;
+        let end = match node.kind {
+            NodeKind::Label(_) => {
+                let name = self.tokens.iter()
+                    .find(|t| t.span.offset > start).unwrap().span;
+                name.offset + name.len
+            },
+            _ => start + node.span.len
+        };
+        let leading = self.comments_before(start);
+        let text = if self.comments_before(end).is_empty() {
+            match node.kind {
+                NodeKind::Label(ref name) => format!("label {}", name),
+                _ => self.tokens.iter()
+                    .filter(|t| t.span.offset >= start && t.span.offset < end)
+                    .fold(String::new(), |mut s, t| {
+                        // arrays get one space between elements, and none
+                        // inside the brackets
+                        if !s.is_empty() && !s.ends_with('[') &&
+                                t.kind != LexemeKind::Close {
+                            s.push(' ');
+                        }
+                        s.push_str(self.text(t.span));
+                        s
+                    })
+            }
+        } else {
+            // keep comments inside an array (or a label) right where they are
+            self.source[start..end].to_string()
+        };
+        let trailing = match self.comments.get(self.next) {
+            Some(c) if self.source[end..c.span.offset].chars()
+                    .all(|ch| ch == ' ' || ch == '\t') => {
+                self.next += 1;
+                Some(self.text(c.span).to_string())
+            },
+            _ => None
+        };
+        let args = match node.kind {
+            NodeKind::Call { ref args, .. } =>
+                args.iter().map(|arg| self.item(arg)).collect(),
+            _ => vec![]
+        };
+        Item { leading, text, trailing, args }
+    }
+
+    fn line(&mut self, indent: usize, text: &str) {
+        self.out.push_str(&" ".repeat(indent));
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn write(&mut self, item: &Item, indent: usize) {
+        for comment in &item.leading { self.line(indent, comment); }
+        if let Some(mut s) = item.flat(true) {
+            if let Some(ref comment) = item.last().trailing {
+                s = format!("{} {}", s, comment);
+            }
+            if indent + s.chars().count() <= WIDTH {
+                self.line(indent, &s);
+                return;
+            }
+        }
+        match item.trailing {
+            Some(ref comment) => {
+                let s = format!("{} {}", item.text, comment);
+                self.line(indent, &s);
+            },
+            None => self.line(indent, &item.text)
+        }
+        for arg in &item.args { self.write(arg, indent + INDENT); }
+    }
+
+    /// Whether there's a blank line in the source before `offset`, since
+    /// whatever came before it.
+    fn blank_before(&self, offset: usize) -> bool {
+        self.source[..offset].chars().rev()
+            .take_while(|ch| ch.is_whitespace())
+            .filter(|&ch| ch == '\n').count() >= 2
+    }
+}
+
+/// Formats `source`, where `arity` gives the number of arguments an
+/// operator takes (as for `parser::parse`).
+pub fn format<F>(source: &str, arity: F) -> Result<String, MacaroniError>
+        where F: Fn(&str) -> Option<usize> {
+    let lexemes = lexer::lex(source)?;
+    let program = parser::parse(&lexemes, arity)?;
+    let mut f = Formatter {
+        source,
+        tokens: lexemes.iter().filter(|l| !l.is_trivia()).collect(),
+        comments: lexemes.iter()
+            .filter(|l| matches!(l.kind, LexemeKind::Comment(_))).collect(),
+        next: 0,
+        out: String::new()
+    };
+    let mut indent = 0;
+    for node in &program {
+        let first = f.comments.get(f.next).map_or(node.span.offset,
+            |c| c.span.offset.min(node.span.offset));
+        if !f.out.is_empty() && f.blank_before(first) { f.out.push('\n'); }
+        let item = f.item(node);
+        if let NodeKind::Label(_) = node.kind {
+            f.write(&item, 0);
+            indent = INDENT;
+        } else {
+            f.write(&item, indent);
+        }
+    }
+    if let Some(first) = f.comments.get(f.next).map(|c| c.span.offset) {
+        if !f.out.is_empty() && f.blank_before(first) { f.out.push('\n'); }
+        for comment in f.comments_before(source.len()) {
+            f.line(indent, &comment);
+        }
+    }
+    Ok(f.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macaroni::Macaroni;
+
+    /// The examples from the README, with something filled in for
+    /// `<stuff>`.
+    const EXAMPLES: &[&str] = &[
+        "# prints \"hi\"\nprint \"hi\"\n\
+         #| print \"this is\n   never printed\" |#\n",
+        "map slice \" \" 0 x 1 a return\nlabel a set x multiply x -1 return\n",
+        "pow pow x 2 pow 2 -1\n",
+        "# x - y * floor(x / y)\n\
+         add x multiply -1 multiply y floor multiply x pow y -1\n",
+        "label loop\n    map\n        map slice \" \" x add x 1 1 func\n\
+         \x20       loop\nreturn\n\nlabel func set x 1 return\n"
+    ];
+
+    fn format(src: &str) -> String {
+        let mac = Macaroni::new();
+        super::format(src, |name| mac.op(name).map(|op| op.arity())).unwrap()
+    }
+
+    #[test]
+    fn idempotent() {
+        for src in EXAMPLES {
+            let once = format(src);
+            assert_eq!(format(&once), once, "formatting {:?}", src);
+        }
+    }
+
+    #[test]
+    fn keeps_comments() {
+        for src in EXAMPLES {
+            let once = format(src);
+            for comment in lexer::lex(src).unwrap().iter()
+                    .filter(|l| matches!(l.kind, LexemeKind::Comment(_))) {
+                let text = &src[comment.span.offset..][..comment.span.len];
+                assert!(once.contains(text), "{:?} lost {:?}", once, text);
+            }
+        }
+    }
+
+    #[test]
+    fn splits_nested_calls() {
+        assert_eq!(format("add x multiply -1 multiply y floor multiply x \
+                           pow y -1"), "\
+add
+    x
+    multiply
+        -1
+        multiply
+            y
+            floor multiply x pow y -1
+");
+        assert_eq!(format("pow pow x 2 pow 2 -1"),
+                   "pow\n    pow x 2\n    pow 2 -1\n");
+        assert_eq!(format("set x multiply x -1"), "set x multiply x -1\n");
+    }
+
+    #[test]
+    fn lays_out() {
+        assert_eq!(format("label a print \"hi\" # greet\nreturn"),
+                   "label a\n    print \"hi\" # greet\n    return\n");
+        let (a, b) = ("a".repeat(30), "b".repeat(30));
+        assert_eq!(format(&format!("add multiply {0} {1} multiply {1} {0}",
+                                   a, b)),
+                   format!("add\n    multiply {0} {1}\n    multiply {1} {0}\n",
+                           a, b));
+    }
+}
@@ -0,0 +1,347 @@
+//! Splits Macaroni source into lexemes, without deciding which identifiers
+//! are operators. The interpreter builds its tokens on top of this, and
+//! anything else that wants to look at source text (editors, formatters)
+//! can use it directly.
+
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use macaroni::MacaroniError;
+
+/// Where a lexeme came from in the source text. `line` and `col` are
+/// 1-based, `col` counts characters, and `offset`/`len` are in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: usize,
+    pub col: usize,
+    /// Which chunk of code given to `Macaroni::run` this is in, counting
+    /// from 1 once it's been accepted; 0 is whatever source was just lexed.
+    pub chunk: usize
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexemeKind {
+    /// An operator, variable or label name.
+    Ident(String),
+    Number(f64),
+    /// The contents of a string literal, without the quotes.
+    Str(String),
+    /// `[`, starting an array literal.
+    Open,
+    /// `]`, ending an array literal.
+    Close,
+    /// A `# line comment` or `#| block comment |#`, delimiters included.
+    Comment(String),
+    Whitespace
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lexeme {
+    pub kind: LexemeKind,
+    pub span: Span
+}
+
+impl Lexeme {
+    /// Whitespace and comments, which don't affect the program.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.kind, LexemeKind::Comment(_) | LexemeKind::Whitespace)
+    }
+}
+
+pub struct Lexer<'a> {
+    src: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    col: usize
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str) -> Lexer<'a> {
+        Lexer { src, chars: src.char_indices().peekable(), line: 1, col: 1 }
+    }
+
+    /// Consumes a char, returning a span covering just it.
+    fn bump(&mut self) -> Option<(char, Span)> {
+        let (offset, ch) = self.chars.next()?;
+        let span = Span {
+            offset, len: ch.len_utf8(), line: self.line, col: self.col,
+            chunk: 0
+        };
+        if ch == '\n' { self.line += 1; self.col = 1; } else { self.col += 1; }
+        Some((ch, span))
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, ch)| ch)
+    }
+
+    fn lex_word(&mut self, first: char, mut span: Span)
+            -> Result<Lexeme, MacaroniError> {
+        let numeric = first.is_ascii_digit() || first == '.' ||
+            (first == '-' &&
+             self.peek().is_some_and(|ch| ch.is_ascii_digit() || ch == '.'));
+        let mut prev = first;
+        while let Some(ch) = self.peek() {
+            if is_word_char(ch) || (numeric && (ch == '.' ||
+                    (ch == '+' && (prev == 'e' || prev == 'E')))) {
+                self.bump();
+                prev = ch;
+            } else if ch == '"' || ch == '/' || ch == '\\' || ch == '.' {
+                let (ch, span) = self.bump().unwrap();
+                return Err(MacaroniError::UnexpectedChar { ch, span });
+            } else { break; }
+        }
+        span.len = self.chars.peek().map_or(self.src.len(), |&(i, _)| i) -
+            span.offset;
+        let text = &self.src[span.offset..span.offset + span.len];
+        let kind = if numeric ||
+                text.chars().all(|ch| ch.is_ascii_digit() || ch == '-') {
+            LexemeKind::Number(parse_number(text).ok_or_else(||
+                MacaroniError::BadNumber { text: text.to_string(), span })?)
+        } else { match text {
+            "inf" => LexemeKind::Number(f64::INFINITY),
+            "-inf" => LexemeKind::Number(f64::NEG_INFINITY),
+            "nan" => LexemeKind::Number(f64::NAN),
+            _ => LexemeKind::Ident(text.to_string())
+        } };
+        Ok(Lexeme { kind, span })
+    }
+
+    fn lex_string(&mut self, mut span: Span) -> Result<Lexeme, MacaroniError> {
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some(('"', end)) => {
+                    span.len = end.offset + end.len - span.offset;
+                    return Ok(Lexeme { kind: LexemeKind::Str(s), span });
+                },
+                Some(('\\', start)) => s.push(self.lex_escape(start)?),
+                Some((ch, _)) => s.push(ch),
+                None => {
+                    span.len = self.src.len() - span.offset;
+                    return Err(MacaroniError::UnterminatedString { span });
+                }
+            }
+        }
+    }
+
+    /// Decodes the escape sequence after a backslash at `span`.
+    fn lex_escape(&mut self, mut span: Span) -> Result<char, MacaroniError> {
+        let ch = match self.bump() {
+            Some((ch, _)) => match ch {
+                'n' => Some('\n'),
+                't' => Some('\t'),
+                'r' => Some('\r'),
+                '0' => Some('\0'),
+                '"' | '\\' => Some(ch),
+                'u' if self.peek() == Some('{') => {
+                    self.bump();
+                    let mut hex = String::new();
+                    while self.peek().is_some_and(|ch| ch.is_ascii_hexdigit()) {
+                        hex.push(self.bump().unwrap().0);
+                    }
+                    if self.peek() == Some('}') {
+                        self.bump();
+                        u32::from_str_radix(&hex, 16).ok()
+                            .and_then(::std::char::from_u32)
+                    } else { None }
+                },
+                _ => None
+            },
+            None => None
+        };
+        span.len = self.chars.peek().map_or(self.src.len(), |&(i, _)| i) -
+            span.offset;
+        ch.ok_or_else(|| MacaroniError::BadEscape {
+            text: self.src[span.offset..span.offset + span.len].to_string(),
+            span
+        })
+    }
+
+    /// Block comments nest, so that commenting out code that already
+    /// contains one works.
+    fn lex_comment(&mut self, mut span: Span) -> Result<Lexeme, MacaroniError> {
+        if self.peek() == Some('|') {
+            self.bump();
+            let mut depth = 1;
+            let mut prev = ' ';
+            while depth > 0 {
+                let ch = match self.bump() {
+                    Some((ch, _)) => ch,
+                    None => {
+                        span.len = self.src.len() - span.offset;
+                        return Err(MacaroniError::UnterminatedComment { span });
+                    }
+                };
+                // reset `prev` after a delimiter so `#|#` doesn't count twice
+                match (prev, ch) {
+                    ('#', '|') => { depth += 1; prev = ' '; },
+                    ('|', '#') => { depth -= 1; prev = ' '; },
+                    _ => prev = ch
+                }
+            }
+        } else {
+            while self.peek().is_some_and(|ch| ch != '\n') { self.bump(); }
+        }
+        span.len = self.chars.peek().map_or(self.src.len(), |&(i, _)| i) -
+            span.offset;
+        let text = &self.src[span.offset..span.offset + span.len];
+        Ok(Lexeme { kind: LexemeKind::Comment(text.to_string()), span })
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Lexeme, MacaroniError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (ch, span) = self.bump()?;
+        Some(match ch {
+            _ if is_word_char(ch) || ch == '.' || ch == '/' || ch == '\\' =>
+                self.lex_word(ch, span),
+            '"' => self.lex_string(span),
+            '#' => self.lex_comment(span),
+            '[' => Ok(Lexeme { kind: LexemeKind::Open, span }),
+            ']' => Ok(Lexeme { kind: LexemeKind::Close, span }),
+            _ if is_whitespace(ch) => {
+                let mut span = span;
+                while self.peek().is_some_and(is_whitespace) {
+                    let (_, end) = self.bump().unwrap();
+                    span.len = end.offset + end.len - span.offset;
+                }
+                Ok(Lexeme { kind: LexemeKind::Whitespace, span })
+            },
+            _ => Err(MacaroniError::UnrecognizedChar { ch, span })
+        })
+    }
+}
+
+/// Lexes all of `src`, stopping at the first error.
+pub fn lex(src: &str) -> Result<Vec<Lexeme>, MacaroniError> {
+    Lexer::new(src).collect()
+}
+
+/// Parses `-12`, `1.5`, `.5`, `3.` or any of those followed by an exponent
+/// like `e9` or `E-3`. Anything else (including Rust's own `inf` and `+1`)
+/// is rejected.
+fn parse_number(text: &str) -> Option<f64> {
+    fn digits(b: &[u8], i: &mut usize) -> usize {
+        let start = *i;
+        while b.get(*i).is_some_and(u8::is_ascii_digit) { *i += 1; }
+        *i - start
+    }
+    let (b, mut i) = (text.as_bytes(), 0);
+    if b.first() == Some(&b'-') { i += 1; }
+    let mut mantissa = digits(b, &mut i);
+    if b.get(i) == Some(&b'.') {
+        i += 1;
+        mantissa += digits(b, &mut i);
+    }
+    if mantissa == 0 { return None; }
+    if b.get(i) == Some(&b'e') || b.get(i) == Some(&b'E') {
+        i += 1;
+        if b.get(i) == Some(&b'+') || b.get(i) == Some(&b'-') { i += 1; }
+        if digits(b, &mut i) == 0 { return None; }
+    }
+    if i == b.len() { text.parse().ok() } else { None }
+}
+
+fn is_word_char(ch: char) -> bool {
+    matches!(ch, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-')
+}
+
+fn is_whitespace(ch: char) -> bool {
+    ch == ' ' || ch == '\n' || ch == '\t' || ch == '\r'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kinds of everything in `src` that isn't whitespace.
+    fn kinds(src: &str) -> Vec<LexemeKind> {
+        lex(src).unwrap().into_iter().map(|l| l.kind)
+            .filter(|k| *k != LexemeKind::Whitespace).collect()
+    }
+
+    fn number(src: &str) -> f64 {
+        match kinds(src)[..] {
+            [LexemeKind::Number(n)] => n,
+            ref other => panic!("{:?} lexed as {:?}", src, other)
+        }
+    }
+
+    fn string(src: &str) -> String {
+        match kinds(src)[..] {
+            [LexemeKind::Str(ref s)] => s.clone(),
+            ref other => panic!("{:?} lexed as {:?}", src, other)
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(number("42"), 42.0);
+        assert_eq!(number("-7"), -7.0);
+        assert_eq!(number("1.5"), 1.5);
+        assert_eq!(number(".5"), 0.5);
+        assert_eq!(number("-.5"), -0.5);
+        assert_eq!(number("5."), 5.0);
+        assert_eq!(number("1e3"), 1000.0);
+        assert_eq!(number("1E+3"), 1000.0);
+        assert_eq!(number("2.5e-1"), 0.25);
+        assert_eq!(number("inf"), f64::INFINITY);
+        assert_eq!(number("-inf"), f64::NEG_INFINITY);
+        assert!(number("nan").is_nan());
+        assert_eq!(kinds("x-1"), [LexemeKind::Ident("x-1".to_string())]);
+    }
+
+    #[test]
+    fn bad_numbers() {
+        for &src in &["-", "--1", "1.2.3", "1e", "1e+", "1x", ".", "1-2"] {
+            match lex(src) {
+                Err(MacaroniError::BadNumber { ref text, span }) => {
+                    assert_eq!(text, src);
+                    assert_eq!((span.offset, span.len), (0, src.len()));
+                },
+                other => panic!("{:?} lexed as {:?}", src, other)
+            }
+        }
+        assert!(matches!(lex("1/2"),
+            Err(MacaroniError::UnexpectedChar { ch: '/', .. })));
+        // `x1.5` isn't `x1` followed by `.5`
+        match lex("set x1.5 2") {
+            Err(MacaroniError::UnexpectedChar { ch: '.', span }) =>
+                assert_eq!(span.offset, 6),
+            other => panic!("x1.5 lexed as {:?}", other)
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(string(r#""a\nb\t\r\0""#), "a\nb\t\r\0");
+        assert_eq!(string(r#""\"\\""#), "\"\\");
+        assert_eq!(string(r#""\u{41}\u{1F600}""#), "A\u{1F600}");
+        assert_eq!(string("\"two\nlines\""), "two\nlines");
+    }
+
+    #[test]
+    fn bad_escapes() {
+        for &(src, bad) in &[(r#""\q""#, r"\q"), (r#""\u41""#, r"\u"),
+                             (r#""\u{41""#, r"\u{41"),
+                             (r#""\u{D800}""#, r"\u{D800}"),
+                             (r#""\u{110000}""#, r"\u{110000}")] {
+            match lex(src) {
+                Err(MacaroniError::BadEscape { ref text, span }) => {
+                    assert_eq!(text, bad);
+                    assert_eq!(span.offset, 1);
+                },
+                other => panic!("{:?} lexed as {:?}", src, other)
+            }
+        }
+        assert!(matches!(lex(r#""abc\""#),
+            Err(MacaroniError::UnterminatedString { .. })));
+        assert!(matches!(lex(r#""\"#),
+            Err(MacaroniError::BadEscape { .. })));
+    }
+}
@@ -1,15 +1,33 @@
 extern crate rand;
 extern crate time;
 
+pub mod lexer;
+pub mod parser;
+pub mod checker;
+pub mod lint;
+pub mod formatter;
+
 pub mod macaroni {
     use rand;
     use time;
     use std::collections::HashMap;
+    use std::error;
     use std::io;
-    use std::io::Write;
+    use std::cell::RefCell;
+    use std::io::{BufRead, BufReader, Cursor, Read, Write};
+    use std::cmp::Ordering;
+    use std::mem;
     use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use checker;
+    use formatter;
+    use lexer;
+    use lint;
+    pub use lexer::Span;
+    use parser::{self, Node, NodeKind};
 
-    const DIGITS: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
     const EPSILON: f64 = 0.000001;
     const PRECISION: i32 = 10;
 
@@ -22,13 +40,319 @@ pub mod macaroni {
     use std::fmt;
     impl fmt::Debug for Val {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            match self {
-                &Val::Num(n) => write!(f, "{}", n),
-                &Val::Arr(ref a) => write!(f, "{:?}", a)
+            match *self {
+                Val::Num(n) => write!(f, "{}", n),
+                Val::Arr(ref a) => write!(f, "{:?}", a)
+            }
+        }
+    }
+
+    /// Shows values the way they'd be written in source, except that arrays
+    /// of printable code points are shown as string literals. Use `Debug` to
+    /// always see the numbers.
+    impl fmt::Display for Val {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Val::Num(n) if n.is_nan() => write!(f, "nan"),
+                Val::Num(n) => write!(f, "{}", n),
+                Val::Arr(ref a) => match Val::as_string(a) {
+                    // Rust's escapes happen to be the same as ours
+                    Some(s) => write!(f, "{:?}", s),
+                    None => {
+                        write!(f, "[")?;
+                        for (i, x) in a.iter().enumerate() {
+                            if i > 0 { write!(f, " ")?; }
+                            write!(f, "{}", x)?;
+                        }
+                        write!(f, "]")
+                    }
+                }
+            }
+        }
+    }
+
+    impl Val {
+        /// The string `a` probably represents, if it's made up entirely of
+        /// printable code points and whitespace, with at least one of the
+        /// former (so that small numbers aren't taken for tabs and newlines).
+        fn as_string(a: &[Val]) -> Option<String> {
+            let s = a.iter().map(|x| match *x {
+                Val::Num(n) => Macaroni::code_point(n).filter(|&c|
+                    !c.is_control() || c == '\n' || c == '\t' || c == '\r'),
+                Val::Arr(_) => None
+            }).collect::<Option<String>>()?;
+            if s.chars().any(|c| !c.is_whitespace()) { Some(s) } else { None }
+        }
+    }
+
+    /// Everything that can go wrong while tokenizing or running a program.
+    ///
+    /// The `Display` impl gives just the message; use `render` to point at
+    /// the offending source.
+    #[derive(Debug)]
+    pub enum MacaroniError {
+        /// An operator was given a number where it wanted an array, or vice
+        /// versa.
+        WrongType { op: String, want: Kind, val: Val, span: Span },
+        /// An operator was given a value of the right type that it still
+        /// can't do anything with.
+        BadValue { op: &'static str, val: Val, msg: &'static str, span: Span },
+        /// A "string" contained a number that isn't a Unicode code point.
+        NotACodePoint { op: &'static str, n: f64, span: Span },
+        /// An operator that needs a variable or label name was given a
+        /// literal.
+        NotAName { op: String, val: Val, span: Span },
+        UnknownLabel { op: String, label: String, span: Span },
+        DuplicateLabel { label: String, first: Span, span: Span },
+        MissingArgument { span: Span },
+        NullArgument { span: Span },
+        LabelArgument { span: Span },
+        UnrecognizedChar { ch: char, span: Span },
+        UnexpectedChar { ch: char, span: Span },
+        BadNumber { text: String, span: Span },
+        UnterminatedString { span: Span },
+        BadEscape { text: String, span: Span },
+        UnterminatedComment { span: Span },
+        BadLabel { span: Span },
+        UnmatchedBracket { span: Span },
+        UnterminatedArray { span: Span },
+        BadArrayElement { span: Span },
+        /// Calls or array literals nested deeper than the parser allows.
+        TooDeep { span: Span },
+        Io { op: &'static str, err: io::Error, span: Span },
+        /// An operator added with `Macaroni::register_op` failed.
+        OpFailed { op: String, msg: String, span: Span },
+        /// `checker::check` found an argument that will always be the wrong
+        /// kind, or isn't a name when it needs to be.
+        KindMismatch { op: String, want: Kind, got: Kind, span: Span },
+        /// The program went over one of the `Limits` it was given.
+        LimitExceeded { limit: Limit, span: Span }
+    }
+
+    impl fmt::Display for MacaroniError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            use self::MacaroniError::*;
+            match *self {
+                WrongType { ref op, want, ref val, .. } => write!(f,
+                    "{} called with {} {:?} instead of {}", op, match *val {
+                        Val::Num(_) => "Num",
+                        Val::Arr(_) => "Arr"
+                    }, val, want),
+                BadValue { op, ref val, msg, .. } => write!(f,
+                    "{} called with {}: {:?}", op, msg, val),
+                NotACodePoint { op, n, .. } => write!(f,
+                    "{} called with {}, which is not a valid code point", op, n),
+                NotAName { ref op, ref val, .. } => write!(f,
+                    "{} called with literal {:?} instead of a name", op, val),
+                UnknownLabel { ref op, ref label, .. } => write!(f,
+                    "{} to unknown label {}", op, label),
+                DuplicateLabel { ref label, first, .. } => write!(f,
+                    "label {} is already defined at {}:{}", label, first.line,
+                    first.col),
+                MissingArgument { .. } => write!(f,
+                    "expected operator argument, found end of program"),
+                NullArgument { .. } => write!(f,
+                    "cannot pass null to operator"),
+                LabelArgument { .. } => write!(f,
+                    "cannot pass label to operator"),
+                UnrecognizedChar { ch, .. } => write!(f,
+                    "unrecognized char {:?}", ch),
+                UnexpectedChar { ch, .. } => write!(f,
+                    "unexpected {:?} in the middle of a token", ch),
+                BadNumber { ref text, .. } => write!(f,
+                    "malformed number {}", text),
+                UnterminatedString { .. } => write!(f,
+                    "unterminated string literal"),
+                BadEscape { ref text, .. } => write!(f,
+                    "unknown escape sequence {}", text),
+                UnterminatedComment { .. } => write!(f,
+                    "unterminated block comment"),
+                BadLabel { .. } => write!(f,
+                    "label must be followed by a name"),
+                UnmatchedBracket { .. } => write!(f, "unmatched bracket"),
+                UnterminatedArray { .. } => write!(f,
+                    "unterminated array literal"),
+                BadArrayElement { .. } => write!(f,
+                    "array literals may only contain numbers, strings and \
+                    arrays"),
+                TooDeep { .. } => write!(f, "nested too deeply"),
+                Io { op, ref err, .. } => write!(f,
+                    "{} failed: {}", op, err),
+                OpFailed { ref op, ref msg, .. } => write!(f,
+                    "{} failed: {}", op, msg),
+                KindMismatch { ref op, want: want @ Kind::Label, .. } |
+                KindMismatch { ref op, want: want @ Kind::Var, .. } =>
+                    write!(f, "{} expects {} name here", op, want),
+                KindMismatch { ref op, want: Kind::Any, .. } => write!(f,
+                    "{} expects a value here, but got nothing", op),
+                KindMismatch { ref op, want, got, .. } => write!(f,
+                    "{} expects {} here, but got {}", op, want, got),
+                LimitExceeded { limit, .. } => match limit {
+                    Limit::Steps(n) => write!(f,
+                        "program ran for more than {} operators", n),
+                    Limit::Time(t) => write!(f,
+                        "program ran for longer than {:?}", t),
+                    Limit::Depth(n) => write!(f,
+                        "callbacks nested more than {} deep", n),
+                    Limit::Memory(n) => write!(f,
+                        "program used more than {} bytes of memory", n)
+                }
+            }
+        }
+    }
+
+    impl error::Error for MacaroniError {}
+
+    impl MacaroniError {
+        pub fn span(&self) -> Span {
+            use self::MacaroniError::*;
+            match *self {
+                WrongType { span, .. } | BadValue { span, .. } |
+                NotACodePoint { span, .. } |
+                NotAName { span, .. } | UnknownLabel { span, .. } |
+                DuplicateLabel { span, .. } |
+                MissingArgument { span } | NullArgument { span } |
+                LabelArgument { span } | UnrecognizedChar { span, .. } |
+                UnexpectedChar { span, .. } | BadNumber { span, .. } |
+                UnterminatedString { span } | BadEscape { span, .. } |
+                UnterminatedComment { span } | BadLabel { span } |
+                UnmatchedBracket { span } | UnterminatedArray { span } |
+                BadArrayElement { span } | TooDeep { span } |
+                Io { span, .. } | OpFailed { span, .. } |
+                KindMismatch { span, .. } | LimitExceeded { span, .. } => span
             }
         }
+
+        /// Whether the error just means the code stopped too early, so that
+        /// more input (another line in the REPL, say) could fix it.
+        pub fn is_incomplete(&self) -> bool {
+            use self::MacaroniError::*;
+            matches!(*self, MissingArgument { .. } | UnterminatedString { .. } |
+                     UnterminatedComment { .. } | UnterminatedArray { .. })
+        }
+
+        /// Formats the error as `name:line:col: message`, followed by the
+        /// offending line of `source` with a caret underneath.
+        pub fn render(&self, name: &str, source: &str) -> String {
+            render(self.span(), &self.to_string(), name, source)
+        }
+    }
+
+    /// Formats `msg` as `name:line:col: msg` for `span`, followed by the line
+    /// of `source` it's in with a caret underneath.
+    pub fn render(span: Span, msg: &str, name: &str, source: &str) -> String {
+        let mut out = format!("{}:{}:{}: {}", name, span.line, span.col, msg);
+        // the span might be from some other source (an earlier line in the
+        // REPL, say), in which case there's nothing to show
+        let line = span.line.checked_sub(1)
+            .and_then(|i| source.lines().nth(i));
+        let fits = source.get(span.offset..span.offset + span.len).is_some();
+        if let (Some(line), true) = (line, fits) {
+            // copy tabs so the caret lines up however they're displayed
+            let indent = line.chars().take(span.col - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+            let width = source[span.offset..]
+                .char_indices().take_while(|&(i, _)| i < span.len).count();
+            out.push_str(&format!("\n{}\n{}{}", line, indent,
+                                  "^".repeat(width.max(1))));
+        }
+        out
+    }
+
+    /// Which limit a program went over, and what it was.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Limit {
+        Steps(u64),
+        Time(Duration),
+        Depth(usize),
+        Memory(usize)
     }
 
+    /// How deeply callbacks may nest unless the `Limits` say otherwise. Each
+    /// one takes up stack, and running out of that can't be recovered from;
+    /// this many fit in a spawned thread's 2 MiB with room to spare.
+    pub const DEFAULT_DEPTH: usize = 100;
+
+    /// Bounds on how much work a single call to `Macaroni::run` may do, for
+    /// running programs that can't be trusted to finish. `None` means
+    /// unlimited, which is the default for everything but `depth`.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Limits {
+        /// How many operators may be applied.
+        pub steps: Option<u64>,
+        /// How long the program may run for.
+        pub time: Option<Duration>,
+        /// How many callbacks from `map`, `sort` or `index` may be running
+        /// inside one another.
+        pub depth: Option<usize>,
+        /// Roughly how many bytes the values in variables, plus whatever an
+        /// operator is building, may take up.
+        pub memory: Option<usize>
+    }
+
+    impl Default for Limits {
+        fn default() -> Limits {
+            Limits {
+                steps: None, time: None, depth: Some(DEFAULT_DEPTH),
+                memory: None
+            }
+        }
+    }
+
+    type OpResult = Result<Option<Variable>, MacaroniError>;
+    type OpFn = Rc<dyn Fn(&mut Macaroni, &[Variable]) -> OpResult>;
+    type BuiltinFn = fn(&mut Macaroni, &[Variable]) -> OpResult;
+
+    /// Every built-in operator: its name, implementation, signature (see
+    /// `OpInfo::new`) and help text.
+    const BUILTINS: &[(&str, BuiltinFn, &str, &str)] = &[
+        ("add", Macaroni::add, "nn -> n", "add two numbers"),
+        ("multiply", Macaroni::multiply, "nn -> n", "multiply two numbers"),
+        ("floor", Macaroni::floor, "n -> n", "round down"),
+        ("pow", Macaroni::pow, "nn -> n",
+         "raise the first number to the power of the second"),
+        ("tobase", Macaroni::tobase, "nn -> a",
+         "write a number as a string in a base from 2 to 36"),
+        ("sort", Macaroni::sort, "al -> a",
+         "sort, calling the label with a pair in `_` to get a negative, zero \
+          or positive comparison back"),
+        ("concat", Macaroni::concat, "aa -> a", "join two arrays"),
+        ("each", Macaroni::each, "an -> a",
+         "every run of n elements in a row (a la Ruby `each_cons`), or \
+          consecutive runs of -n (`each_slice`) if n is negative"),
+        ("map", Macaroni::map, "al -> a",
+         "call the label with each element in `_`, collecting what it leaves \
+          there"),
+        ("index", Macaroni::index, "al -> a",
+         "indices of the elements for which the label leaves something other \
+          than 0 or [] in `_`"),
+        ("slice", Macaroni::slice, "annn -> a",
+         "elements from a start up to an end by a step (a la Python \
+          `a[b:c:d]`)"),
+        ("length", Macaroni::length, "a -> n", "number of elements"),
+        ("transpose", Macaroni::transpose, "a -> a",
+         "swap the rows and columns of an array of arrays"),
+        ("flatten", Macaroni::flatten, "an -> a",
+         "flatten n levels of nesting, or all of them if n is 0"),
+        ("frombase", Macaroni::frombase, "an -> n",
+         "read a string as a number in a base from 2 to 36"),
+        ("wrap", Macaroni::wrap, "* -> a", "put in an array by itself"),
+        ("unwrap", Macaroni::unwrap, "a -> *",
+         "the only element of an array of length 1"),
+        ("print", Macaroni::print, "a -> -", "print a string"),
+        ("read", Macaroni::read, "- -> a",
+         "read a line of input, newline included"),
+        ("rand", Macaroni::rand, "- -> n", "a random number in [0, 1)"),
+        ("time", Macaroni::time, "- -> n", "seconds since the Unix epoch"),
+        ("set", Macaroni::set, "v* -> *",
+         "set a variable, giving back its new value"),
+        ("goto", Macaroni::goto, "l -> -", "jump to a label"),
+        ("return", Macaroni::return_, "- -> -",
+         "go back to just after the last goto, or finish the callback or \
+          program if there isn't one")
+    ];
+
     #[derive(Clone)]
     struct Variable {
         val: Val,
@@ -47,360 +371,828 @@ pub mod macaroni {
         }
     }
 
+    /// What an operator takes or gives back, written in signatures as the
+    /// character in brackets: a number (`n`), an array (`a`), a label (`l`),
+    /// a variable (`v`), anything (`*`) or nothing (`-`).
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Kind { Num, Arr, Label, Var, Any, Nothing }
+
+    impl Kind {
+        pub fn code(self) -> char {
+            match self {
+                Kind::Num => 'n', Kind::Arr => 'a', Kind::Label => 'l',
+                Kind::Var => 'v', Kind::Any => '*', Kind::Nothing => '-'
+            }
+        }
+
+        pub fn from_code(code: char) -> Option<Kind> {
+            [Kind::Num, Kind::Arr, Kind::Label, Kind::Var, Kind::Any,
+             Kind::Nothing].iter().cloned().find(|k| k.code() == code)
+        }
+    }
+
+    impl fmt::Display for Kind {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(match *self {
+                Kind::Num => "a number", Kind::Arr => "an array",
+                Kind::Label => "a label", Kind::Var => "a variable",
+                Kind::Any => "anything", Kind::Nothing => "nothing"
+            })
+        }
+    }
+
+    /// Everything there is to know about an operator besides what it does.
+    #[derive(Clone, Debug)]
+    pub struct OpInfo {
+        pub name: String,
+        /// One per argument; never `Kind::Nothing`.
+        pub args: Vec<Kind>,
+        pub ret: Kind,
+        pub help: String
+    }
+
+    impl OpInfo {
+        /// Describes an operator by signature, like `OpInfo::new("add",
+        /// "nn -> n", "add two numbers")`. Takes no arguments if the part
+        /// before the arrow is `-`. Panics if the signature is malformed.
+        pub fn new(name: &str, signature: &str, help: &str) -> OpInfo {
+            let kinds = |s: &str| s.trim().chars().map(|c| Kind::from_code(c)
+                .unwrap_or_else(|| panic!("bad signature {:?}", signature)))
+                .collect::<Vec<_>>();
+            let (args, ret) = match signature.split_once("->") {
+                Some((args, ret)) => (kinds(args), kinds(ret)),
+                None => panic!("bad signature {:?}", signature)
+            };
+            let args = if args == [Kind::Nothing] { vec![] } else { args };
+            assert!(!args.contains(&Kind::Nothing) && ret.len() == 1,
+                    "bad signature {:?}", signature);
+            OpInfo {
+                name: name.to_string(), args, ret: ret[0],
+                help: help.to_string()
+            }
+        }
+
+        pub fn arity(&self) -> usize { self.args.len() }
+
+        /// The signature in the form `OpInfo::new` takes, like `nn -> n`.
+        pub fn signature(&self) -> String {
+            let args = if self.args.is_empty() { "-".to_string() }
+                else { self.args.iter().map(|k| k.code()).collect() };
+            format!("{} -> {}", args, self.ret.code())
+        }
+
+        /// Which argument, if any, names a label to jump to.
+        fn label_arg(&self) -> Option<usize> {
+            self.args.iter().position(|&k| k == Kind::Label)
+        }
+    }
+
     #[derive(Clone)]
-    enum Token {
-        Var(Variable),
-        Op {
-            func: Rc<Fn(&mut Macaroni, &[Variable]) -> Option<Variable>>,
-            arity: usize
-        },
-        Label
+    struct Op {
+        func: OpFn,
+        info: Rc<OpInfo>
+    }
+
+    /// An argument as seen by a `Hook`: its value, and the variable it came
+    /// from if any (which is what `set`, `goto` and the like look at).
+    #[derive(Clone, Debug)]
+    pub struct Arg {
+        pub val: Val,
+        pub var: Option<String>
+    }
+
+    impl fmt::Display for Arg {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.var {
+                Some(ref var) => write!(f, "{}={}", var, self.val),
+                None => write!(f, "{}", self.val)
+            }
+        }
+    }
+
+    /// Gets told about each step a program takes, for debuggers and the like.
+    /// See `Macaroni::set_hook`.
+    pub trait Hook {
+        /// Called just before operator `op` at `span` is applied to `args`.
+        fn before_op(&mut self, _mac: &Macaroni, _op: &str, _args: &[Arg],
+                     _span: Span) {}
+        /// Called once `op` has been applied to `args`, with what it
+        /// returned. Not called if it failed.
+        fn after_op(&mut self, _mac: &Macaroni, _op: &str, _args: &[Arg],
+                    _result: Option<&Val>, _span: Span) {}
+        /// Called when execution reaches `label name`, whether by falling
+        /// into it, `goto`, or a callback from `map`, `sort` or `index`.
+        fn on_label(&mut self, _mac: &Macaroni, _name: &str, _span: Span) {}
+        /// Called when the `goto` at `span` jumps to the label `name` at
+        /// `to`.
+        fn on_goto(&mut self, _mac: &Macaroni, _name: &str, _span: Span,
+                   _to: Span) {}
+        /// Called when the `return` at `span` goes back to the statement
+        /// containing the last `goto`, or with `None` if there wasn't one
+        /// and the state ends.
+        fn on_return(&mut self, _mac: &Macaroni, _span: Span,
+                     _to: Option<Span>) {}
+        /// Called when `op` (`map`, `sort` or `index`) at `span` is about to
+        /// run the label `name` with `_` set to `arg`.
+        fn on_callback(&mut self, _mac: &Macaroni, _op: &str, _name: &str,
+                       _arg: &Val, _span: Span) {}
+    }
+
+    /// A snapshot of one of the states a program is running in: there's one
+    /// for the code passed to `run`, plus one for each callback from `map`,
+    /// `sort` or `index` that hasn't finished yet.
+    #[derive(Clone, Debug)]
+    pub struct StateInfo {
+        /// The label the state started at, if it's a callback.
+        pub label: Option<String>,
+        /// The operator most recently applied.
+        pub op: Span,
+        /// The statements containing each `goto` that hasn't been returned
+        /// from yet, oldest first.
+        pub gotos: Vec<Span>
     }
 
     struct State {
+        /// Where the state started running, for `StateInfo::label`.
+        start: usize,
         i: usize,
-        call_stack: Vec<usize>
+        /// Where the chunk of the program `i` is in ends.
+        end: usize,
+        /// Position of the operator currently being applied, for errors.
+        op: Span,
+        /// `(i, end)` to go back to for each pending `goto`.
+        call_stack: Vec<(usize, usize)>
     }
 
     pub struct Macaroni {
         vars: HashMap<String, Val>,
-        program: Vec<Token>,
-        states: Vec<State>
+        ops: HashMap<String, Op>,
+        program: Rc<Vec<Node>>,
+        /// Statement index of each label in `program`.
+        labels: HashMap<String, usize>,
+        /// Where each chunk of `program` (one per call to `run`) ends.
+        chunk_ends: Vec<usize>,
+        /// The source of each chunk, to show errors in.
+        sources: Vec<String>,
+        states: Vec<State>,
+        hook: Option<Box<dyn Hook>>,
+        limits: Limits,
+        /// Operators applied, and when, since `run` was last called.
+        steps: u64,
+        started: Instant,
+        /// Approximate size of everything in `vars`, in bytes.
+        memory: usize,
+        /// Where `read` gets its lines from; `None` is stdin, which isn't
+        /// buffered here so nothing else reading it misses out.
+        input: Option<Box<dyn BufRead>>,
+        output: Box<dyn Write>
+    }
+
+    /// Output written by a `Macaroni` made with `Macaroni::in_memory`.
+    #[derive(Clone, Default)]
+    pub struct MemoryOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl MemoryOutput {
+        /// Everything printed so far.
+        pub fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.borrow()).into_owned()
+        }
+    }
+
+    impl Write for MemoryOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Default for Macaroni {
+        fn default() -> Macaroni { Macaroni::new() }
     }
 
     impl Macaroni {
+        /// Makes an interpreter that reads from stdin and prints to stdout.
         pub fn new() -> Macaroni {
+            Macaroni::with_input(None, Box::new(io::stdout()))
+        }
+
+        /// Makes an interpreter that reads lines from `input` and prints to
+        /// `output`.
+        pub fn with_io<R, W>(input: R, output: W) -> Macaroni
+                where R: Read + 'static, W: Write + 'static {
+            Macaroni::with_input(Some(Box::new(BufReader::new(input))),
+                                 Box::new(output))
+        }
+
+        /// Makes an interpreter that reads lines from `input`, and keeps
+        /// what it prints in the returned `MemoryOutput`.
+        pub fn in_memory(input: &str) -> (Macaroni, MemoryOutput) {
+            let output = MemoryOutput::default();
+            let mac = Macaroni::with_io(Cursor::new(input.as_bytes().to_vec()),
+                                        output.clone());
+            (mac, output)
+        }
+
+        fn with_input(input: Option<Box<dyn BufRead>>, output: Box<dyn Write>)
+                -> Macaroni {
+            let ops = BUILTINS.iter().map(|&(name, func, signature, help)|
+                (name.to_string(), Op {
+                    func: Rc::new(func),
+                    info: Rc::new(OpInfo::new(name, signature, help))
+                })
+            ).collect();
             Macaroni {
-                vars: HashMap::<String, Val>::new(),
-                program: vec![], states: vec![]
+                vars: HashMap::<String, Val>::new(), ops,
+                program: Rc::new(vec![]), labels: HashMap::new(),
+                chunk_ends: vec![], sources: vec![], states: vec![], hook: None,
+                limits: Limits::default(), steps: 0, started: Instant::now(),
+                memory: 0, input, output
             }
         }
 
-        pub fn run(&mut self, code: String) -> Option<Val> {
-            let tokens = self.tokenize(code);
-            self.program = tokens;
-            self.run_tokens(0)
+        /// Runs `code`, returning the value of its last statement.
+        ///
+        /// Calling this again keeps everything defined so far: variables, as
+        /// well as labels, which later code can `goto` or `map` with (or
+        /// redefine). Each chunk of code still runs on its own, though, so
+        /// falling off the end of one never runs into the next.
+        pub fn run(&mut self, code: String)
+                -> Result<Option<Val>, MacaroniError> {
+            // in case an earlier run panicked partway through
+            self.states.clear();
+            let mut chunk = self.parse(&code)?;
+            let start = self.program.len();
+            let mut labels = self.labels.clone();
+            labels.extend(Macaroni::find_labels(&chunk, start)?);
+            for node in &chunk { self.check_labels(node, &labels)?; }
+            for node in &mut chunk {
+                parser::set_chunk(node, self.sources.len() + 1);
+            }
+            Rc::make_mut(&mut self.program).extend(chunk);
+            self.chunk_ends.push(self.program.len());
+            self.sources.push(code);
+            self.labels = labels;
+            self.steps = 0;
+            self.started = Instant::now();
+            self.run_tokens(start)
         }
 
-        fn tokenize(&self, code: String) -> Vec<Token> {
-            let mut tokens = Vec::<String>::new();
-            let mut token = String::new();
-            for ch in code.chars() {
-                if token.starts_with("\"") {
-                    token.push(ch);
-                    if ch == '"' {
-                        tokens.push(token);
-                        token = String::new();
-                    }
-                } else { match ch {
-                    'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => {
-                        token.push(ch);
-                    },
-                    ' ' | '\n' | '\t' => {
-                        if !token.is_empty() {
-                            tokens.push(token);
-                            token = String::new();
-                        }
-                    },
-                    '"' | '/' | '\\' => {
-                        if token.is_empty() {
-                            token.push(ch);
-                        } else {
-                            panic!("unexpected {}", token);
-                        }
-                    },
-                    _ => { panic!("unrecognized char {}", ch); }
-                } }
-            }
-            if !token.is_empty() { tokens.push(token); }
-            tokens.iter().map(|t|
-                if t.chars().all(|ch| ch.is_digit(10) || ch == '-') {
-                    Token::Var(Variable::new_num(t.parse::<f64>().unwrap()))
-                } else { match t.chars().next().unwrap() {
-                    '"' => {
-                        Token::Var(Variable::new_arr(Macaroni::string_to_arr(
-                            &t[1..t.len() - 1].to_string())))
-                    },
-                    _ => { match &t[..] {
-                        "add" => Token::Op {
-                            func: Rc::new(Macaroni::add), arity: 2
-                        },
-                        "multiply" => Token::Op {
-                            func: Rc::new(Macaroni::multiply), arity: 2
-                        },
-                        "floor" => Token::Op {
-                            func: Rc::new(Macaroni::floor), arity: 1
-                        },
-                        "pow" => Token::Op {
-                            func: Rc::new(Macaroni::pow), arity: 2
-                        },
-                        "tobase" => Token::Op {
-                            func: Rc::new(Macaroni::tobase), arity: 2
-                        },
-                        "sort" => Token::Op {
-                            func: Rc::new(Macaroni::sort), arity: 2
-                        },
-                        "concat" => Token::Op {
-                            func: Rc::new(Macaroni::concat), arity: 2
-                        },
-                        "each" => Token::Op {
-                            func: Rc::new(Macaroni::each), arity: 2
-                        },
-                        "map" => Token::Op {
-                            func: Rc::new(Macaroni::map), arity: 2
-                        },
-                        "index" => Token::Op {
-                            func: Rc::new(Macaroni::index), arity: 2
-                        },
-                        "length" => Token::Op {
-                            func: Rc::new(Macaroni::length), arity: 1
-                        },
-                        "transpose" => Token::Op {
-                            func: Rc::new(Macaroni::transpose), arity: 1
-                        },
-                        "flatten" => Token::Op {
-                            func: Rc::new(Macaroni::flatten), arity: 2
-                        },
-                        "frombase" => Token::Op {
-                            func: Rc::new(Macaroni::frombase), arity: 2
-                        },
-                        "slice" => Token::Op {
-                            func: Rc::new(Macaroni::slice), arity: 4
-                        },
-                        "wrap" => Token::Op {
-                            func: Rc::new(Macaroni::wrap), arity: 1
-                        },
-                        "unwrap" => Token::Op {
-                            func: Rc::new(Macaroni::unwrap), arity: 1
-                        },
-                        "print" => Token::Op {
-                            func: Rc::new(Macaroni::print), arity: 1
-                        },
-                        "read" => Token::Op {
-                            func: Rc::new(Macaroni::read), arity: 0
-                        },
-                        "rand" => Token::Op {
-                            func: Rc::new(Macaroni::rand), arity: 0
-                        },
-                        "time" => Token::Op {
-                            func: Rc::new(Macaroni::time), arity: 0
-                        },
-                        "set" => Token::Op {
-                            func: Rc::new(Macaroni::set), arity: 2
-                        },
-                        "label" => Token::Label,
-                        "goto" => Token::Op {
-                            func: Rc::new(Macaroni::goto), arity: 1
-                        },
-                        "return" => Token::Op {
-                            func: Rc::new(Macaroni::return_), arity: 0
-                        },
-                        _ => Token::Var(Variable::by_name(t.clone()))
-                    } }
-                } }).collect::<Vec<Token>>()
-        }
-
-        fn run_tokens(&mut self, from: usize) -> Option<Val> {
-            self.states.insert(0, State { i: from, call_stack: vec![] });
-            let mut last_val: Option<Val> = None;
-            loop {
-                let t = if let Some(x) = self.program.get(self.states[0].i) {
-                    x.clone()
-                } else { break };
-                match t {
-                    Token::Op { .. } => {
-                        last_val = self.execute_op().map(|x| x.val);
-                    },
-                    Token::Var(ref v) => {
-                        last_val = Some(self.uv(v).val);
-                        self.states[0].i += 1;
-                    },
-                    Token::Label => {
-                        self.states[0].i += 2;
+        pub fn vars(&self) -> &HashMap<String, Val> {
+            &self.vars
+        }
+
+        /// The names of all labels defined so far, sorted.
+        pub fn labels(&self) -> Vec<&str> {
+            let mut labels = self.labels.keys().map(|l| &l[..])
+                .collect::<Vec<_>>();
+            labels.sort();
+            labels
+        }
+
+        /// Forgets all variables and everything that has been run.
+        pub fn reset(&mut self) {
+            self.vars.clear();
+            self.memory = 0;
+            self.program = Rc::new(vec![]);
+            self.labels.clear();
+            self.chunk_ends.clear();
+            self.sources.clear();
+        }
+
+        /// The source `span` points into: that of the chunk it's from, or
+        /// `None` if it's from code that was never run (which the caller
+        /// should already have).
+        pub fn source(&self, span: Span) -> Option<&str> {
+            span.chunk.checked_sub(1).and_then(|i| self.sources.get(i))
+                .map(|s| &s[..])
+        }
+
+        /// Adds an operator called `name` that takes `arity` arguments, or
+        /// replaces the one already called that. `func` gets the values of
+        /// the arguments, and can fail with a message, which is reported as
+        /// `MacaroniError::OpFailed`.
+        ///
+        /// Code that has already been run stays parsed the way it was, so
+        /// changing an operator's arity is best done before running
+        /// anything. Panics if `name` isn't something the lexer would read
+        /// as a name.
+        pub fn register_op<F>(&mut self, name: &str, arity: usize, func: F)
+                where F: Fn(&[Val]) -> Result<Option<Val>, String> + 'static {
+            let info = OpInfo {
+                name: name.to_string(), args: vec![Kind::Any; arity],
+                ret: Kind::Any, help: String::new()
+            };
+            self.register_op_with(info, func);
+        }
+
+        /// Like `register_op`, but describing the operator fully. Arguments
+        /// are checked against `info.args` before `func` sees them, and a
+        /// `Kind::Label` argument must name a label that exists, though
+        /// only `goto` and the built-in callbacks can actually run one.
+        pub fn register_op_with<F>(&mut self, info: OpInfo, func: F)
+                where F: Fn(&[Val]) -> Result<Option<Val>, String> + 'static {
+            let name = info.name.clone();
+            let is_name = match lexer::lex(&name).as_ref().map(|l| &l[..]) {
+                Ok([lexer::Lexeme { kind: lexer::LexemeKind::Ident(ref ident),
+                                    .. }]) => ident != "label",
+                _ => false
+            };
+            assert!(is_name, "{:?} can't be used as an operator name", name);
+            let op = name.clone();
+            self.ops.insert(name, Op {
+                func: Rc::new(move |mac: &mut Macaroni, args: &[Variable]| {
+                    let vals = args.iter().map(|a| a.val.clone())
+                        .collect::<Vec<_>>();
+                    match func(&vals) {
+                        Ok(val) => Ok(val.map(|val| Variable { val, var: None })),
+                        Err(msg) => Err(MacaroniError::OpFailed {
+                            op: op.clone(), msg, span: mac.span()
+                        })
                     }
+                }),
+                info: Rc::new(info)
+            });
+        }
+
+        /// Describes the operator called `name`, if there is one.
+        pub fn op(&self, name: &str) -> Option<&OpInfo> {
+            self.ops.get(name).map(|op| &*op.info)
+        }
+
+        /// Describes every operator, sorted by name.
+        pub fn ops(&self) -> Vec<&OpInfo> {
+            let mut ops = self.ops.values().map(|op| &*op.info)
+                .collect::<Vec<_>>();
+            ops.sort_by(|a, b| a.name.cmp(&b.name));
+            ops
+        }
+
+        /// Has `hook` told about everything that happens from now on.
+        pub fn set_hook<H: Hook + 'static>(&mut self, hook: H) {
+            self.hook = Some(Box::new(hook));
+        }
+
+        /// Makes every later call to `run` fail with `LimitExceeded` once
+        /// it goes over `limits`.
+        pub fn set_limits(&mut self, limits: Limits) {
+            self.limits = limits;
+        }
+
+        /// The states currently running, innermost first.
+        pub fn states(&self) -> Vec<StateInfo> {
+            let outermost = self.states.len().saturating_sub(1);
+            self.states.iter().enumerate().map(|(i, state)| StateInfo {
+                // the outermost state is the one `run` started, which might
+                // happen to start with a label too
+                label: match self.program.get(state.start).map(|n| &n.kind) {
+                    Some(NodeKind::Label(name)) if i < outermost =>
+                        Some(name.clone()),
+                    _ => None
+                },
+                op: state.op,
+                gotos: state.call_stack.iter()
+                    .map(|&(i, _)| self.program[i - 1].span).collect()
+            }).collect()
+        }
+
+        /// Parses `code` using the operators this interpreter knows about,
+        /// without running it.
+        pub fn parse(&self, code: &str) -> Result<Vec<Node>, MacaroniError> {
+            parser::parse(&lexer::lex(code)?,
+                          |name| self.ops.get(name).map(|op| op.info.arity()))
+        }
+
+        /// Parses `code` and looks for arguments that will always be the
+        /// wrong kind when it's run, without running it.
+        pub fn check(&self, code: &str)
+                -> Result<Vec<MacaroniError>, MacaroniError> {
+            let program = self.parse(code)?;
+            Ok(checker::check(&program, &self.program, |name| self.op(name),
+                              &self.vars))
+        }
+
+        /// Parses `code` and works out what kind of value it would evaluate
+        /// to, along with anything `check` would find, without running it.
+        pub fn infer(&self, code: &str)
+                -> Result<(Kind, Vec<MacaroniError>), MacaroniError> {
+            let program = self.parse(code)?;
+            Ok(checker::infer(&program, &self.program, |name| self.op(name),
+                              &self.vars))
+        }
+
+        /// Parses `code` and looks for likely mistakes in it; see `lint`.
+        pub fn lint(&self, code: &str)
+                -> Result<Vec<lint::Warning>, MacaroniError> {
+            let program = self.parse(code)?;
+            Ok(lint::lint(&program, |name| self.op(name)))
+        }
+
+        /// Formats `code` using the operators this interpreter knows about;
+        /// see `formatter`.
+        pub fn format(&self, code: &str) -> Result<String, MacaroniError> {
+            formatter::format(code, |name| self.op(name).map(|op| op.arity()))
+        }
+
+        fn run_tokens(&mut self, from: usize)
+                -> Result<Option<Val>, MacaroniError> {
+            let op = self.program.get(from).map_or(Span::default(), |n| n.span);
+            let end = self.chunk_end(from);
+            // `run` itself doesn't count, just the callbacks inside it
+            if let Some(depth) = self.limits.depth {
+                if self.states.len() > depth {
+                    return Err(MacaroniError::LimitExceeded {
+                        limit: Limit::Depth(depth), span: self.span()
+                    });
                 }
             }
+            self.states.insert(0, State {
+                start: from, i: from, end, op, call_stack: vec![]
+            });
+            let result = self.run_state();
             self.states.remove(0);
-            last_val
+            result
         }
 
-        fn execute_op(&mut self) -> Option<Variable> {
-            let (func, arity) = match self.program.get(self.states[0].i)
-                    .unwrap().clone() {
-                Token::Op { func, arity } => (func, arity),
-                _ => unreachable!()
-            };
-            self.states[0].i += 1;
-            let mut args: Vec<Variable> = Vec::with_capacity(arity);
-            while args.len() < arity {
-                match self.program.get(self.states[0].i).expect(
-                        &format!("{:#08x}: expected operator argument, found \
-                                 end of program", self.states[0].i)).clone() {
-                    Token::Var(ref v) => {
-                        args.push(self.uv(v));
-                        self.states[0].i += 1;
-                    },
-                    Token::Op { .. } => {
-                        match self.execute_op() {
-                            Some(v) => args.push(v),
-                            None => panic!("{:#08x}: cannot pass null to \
-                                           operator", self.states[0].i)
+        fn run_state(&mut self) -> Result<Option<Val>, MacaroniError> {
+            let program = self.program.clone();
+            let mut last_val: Option<Val> = None;
+            while self.states[0].i < self.states[0].end {
+                let node = &program[self.states[0].i];
+                // step past the statement first, so that `goto` returns to
+                // the one after it
+                self.states[0].i += 1;
+                if let NodeKind::Label(ref name) = node.kind {
+                    self.notify(|hook, mac| hook.on_label(mac, name, node.span));
+                    continue;
+                }
+                last_val = self.eval(node)?.map(|x| x.val);
+            }
+            Ok(last_val)
+        }
+
+        fn eval(&mut self, node: &Node) -> OpResult {
+            match node.kind {
+                NodeKind::Call { op: ref op_name, ref args } => {
+                    let op = self.ops[op_name].clone();
+                    let mut vals = Vec::with_capacity(args.len());
+                    for arg in args {
+                        match self.eval(arg)? {
+                            Some(v) => vals.push(v),
+                            None => return Err(MacaroniError::NullArgument {
+                                span: arg.span
+                            })
                         }
+                    }
+                    self.states[0].op = node.span;
+                    self.check_args(&op.info, &vals)?;
+                    self.count_step()?;
+                    self.notify(|hook, mac| {
+                        let args = Macaroni::hook_args(&vals);
+                        hook.before_op(mac, op_name, &args, node.span)
+                    });
+                    let result = (op.func)(self, &vals[..])?;
+                    // a variable's value has already been counted
+                    if let Some(Variable { ref val, var: None }) = result {
+                        self.reserve(Macaroni::size(val))?;
+                    }
+                    self.notify(|hook, mac| {
+                        let args = Macaroni::hook_args(&vals);
+                        hook.after_op(mac, op_name, &args,
+                                      result.as_ref().map(|v| &v.val), node.span)
+                    });
+                    Ok(result)
+                },
+                NodeKind::Literal(ref val) =>
+                    Ok(Some(Variable { val: val.clone(), var: None })),
+                NodeKind::VarRef(ref name) =>
+                    Ok(Some(self.uv(&Variable::by_name(name.clone())))),
+                NodeKind::Label(_) => unreachable!()
+            }
+        }
+
+        /// Makes sure each argument is the kind `info` says it should be.
+        fn check_args(&self, info: &OpInfo, args: &[Variable])
+                -> Result<(), MacaroniError> {
+            for (&kind, arg) in info.args.iter().zip(args) {
+                let ok = match (kind, &arg.val) {
+                    (Kind::Num, &Val::Num(_)) | (Kind::Arr, &Val::Arr(_)) =>
+                        true,
+                    (Kind::Num, _) | (Kind::Arr, _) => false,
+                    (Kind::Label, _) | (Kind::Var, _) => {
+                        if arg.var.is_none() {
+                            return Err(MacaroniError::NotAName {
+                                op: info.name.clone(), val: arg.val.clone(),
+                                span: self.span()
+                            });
+                        }
+                        true
                     },
-                    Token::Label => panic!("{:#08x}: cannot pass label to \
-                                           operator", 1)
+                    (Kind::Any, _) | (Kind::Nothing, _) => true
+                };
+                if !ok {
+                    return Err(MacaroniError::WrongType {
+                        op: info.name.clone(), want: kind, val: arg.val.clone(),
+                        span: self.span()
+                    });
                 }
             }
-            func(self, &args[..])
+            Ok(())
+        }
+
+        fn count_step(&mut self) -> Result<(), MacaroniError> {
+            self.steps += 1;
+            let limit = match self.limits {
+                Limits { steps: Some(n), .. } if self.steps > n =>
+                    Limit::Steps(n),
+                Limits { time: Some(t), .. } if self.started.elapsed() > t =>
+                    Limit::Time(t),
+                _ => return Ok(())
+            };
+            Err(MacaroniError::LimitExceeded { limit, span: self.span() })
+        }
+
+        /// Fails if `bytes` more than the variables already use would go
+        /// over the memory limit.
+        fn reserve(&self, bytes: usize) -> Result<(), MacaroniError> {
+            match self.limits.memory {
+                Some(n) if self.memory.saturating_add(bytes) > n =>
+                    Err(MacaroniError::LimitExceeded {
+                        limit: Limit::Memory(n), span: self.span()
+                    }),
+                _ => Ok(())
+            }
+        }
+
+        /// Roughly how many bytes `val` takes up.
+        fn size(val: &Val) -> usize {
+            mem::size_of::<Val>() + match *val {
+                Val::Num(_) => 0,
+                Val::Arr(ref a) => a.iter().map(Macaroni::size).sum()
+            }
+        }
+
+        fn set_var(&mut self, name: String, val: Val)
+                -> Result<(), MacaroniError> {
+            let size = Macaroni::size(&val);
+            let old = self.vars.get(&name).map_or(0, Macaroni::size);
+            self.memory -= old;
+            if let Err(e) = self.reserve(size) {
+                self.memory += old;
+                return Err(e);
+            }
+            self.memory += size;
+            self.vars.insert(name, val);
+            Ok(())
+        }
+
+        fn hook_args(vals: &[Variable]) -> Vec<Arg> {
+            vals.iter().map(|v| Arg { val: v.val.clone(), var: v.var.clone() })
+                .collect()
+        }
+
+        fn notify<F: FnOnce(&mut dyn Hook, &Macaroni)>(&mut self, f: F) {
+            // take the hook out so it can look at everything else
+            if let Some(mut hook) = self.hook.take() {
+                f(&mut *hook, self);
+                self.hook = Some(hook);
+            }
+        }
+
+        /// Runs the label at `target` as a callback from `op`, with `_`
+        /// already set to its argument.
+        fn callback(&mut self, op: &'static str, target: usize)
+                -> Result<(), MacaroniError> {
+            let program = self.program.clone();
+            if let NodeKind::Label(ref name) = program[target].kind {
+                let span = self.span();
+                self.notify(|hook, mac| hook.on_callback(mac, op, name,
+                                                         &mac.vars["_"], span));
+            }
+            self.run_tokens(target).map(|_| ())
         }
 
         /// Updates the `val` part of a variable, when `var` is Some().
         fn uv(&self, v: &Variable) -> Variable {
             if let Some(ref var_name) = v.var {
                 Variable {
-                    val: self.vars.get(var_name)
-                        .map_or(Val::Num(0f64), |x| x.clone()),
+                    val: self.vars.get(var_name).cloned()
+                        .unwrap_or(Val::Num(0f64)),
                     var: v.var.clone()
                 }
             } else { v.clone() }
         }
 
-        fn find_label(&self, desired_label: &str) -> Option<usize> {
-            for i in 0..self.program.len() - 1 {
-                match self.program[i] {
-                    Token::Label => match self.program[i + 1] {
-                        Token::Var(ref v) => match v.var {
-                            Some(ref name) => if name == desired_label {
-                                return Some(i);
-                            },
-                            _ => ()
-                        },
-                        _ => ()
-                    },
-                    _ => ()
+        /// Finds the labels in a chunk that will be put at `start`.
+        fn find_labels(chunk: &[Node], start: usize)
+                -> Result<HashMap<String, usize>, MacaroniError> {
+            let mut labels = HashMap::<String, usize>::new();
+            for (i, node) in chunk.iter().enumerate() {
+                if let NodeKind::Label(ref name) = node.kind {
+                    if let Some(&first) = labels.get(name) {
+                        return Err(MacaroniError::DuplicateLabel {
+                            label: name.clone(),
+                            first: chunk[first - start].span, span: node.span
+                        });
+                    }
+                    labels.insert(name.clone(), start + i);
                 }
             }
-            None
-        }
-
-        fn add(&mut self, args: &[Variable]) -> Option<Variable> {
-            Some(Variable::new_num(match args[0].val {
-                Val::Num(n) => n,
-                Val::Arr(_) => panic!("add called with Arr")
-            } + match args[1].val {
-                Val::Num(n) => n,
-                Val::Arr(_) => panic!("add called with Arr")
-            }))
-        }
-
-        fn multiply(&mut self, args: &[Variable]) -> Option<Variable> {
-            Some(Variable::new_num(match args[0].val {
-                Val::Num(n) => n,
-                Val::Arr(_) => panic!("multiply called with Arr")
-            } * match args[1].val {
-                Val::Num(n) => n,
-                Val::Arr(_) => panic!("multiply called with Arr")
-            }))
-        }
-
-        fn floor(&mut self, args: &[Variable]) -> Option<Variable> {
-            Some(Variable::new_num(match args[0].val {
-                Val::Num(n) => n,
-                Val::Arr(_) => panic!("floor called with Arr")
-            }.floor()))
-        }
-
-        fn pow(&mut self, args: &[Variable]) -> Option<Variable> {
-            Some(Variable::new_num(match args[0].val {
-                Val::Num(n) => n,
-                Val::Arr(_) => panic!("pow called with Arr")
-            }.powf(match args[1].val {
-                Val::Num(n) => n,
-                Val::Arr(_) => panic!("pow called with Arr")
-            })))
-        }
-
-        fn tobase(&mut self, args: &[Variable]) -> Option<Variable> {
-            match args[0].val { Val::Num(n) => {
-                match args[1].val { Val::Num(m) => {
-                    let (base, mut ipart, mut fpart) =
-                        (m as i64, n.floor() as i64, n - n.floor());
-                    let neg = ipart < 0;
-                    if neg { ipart = -ipart; }
-
-                    // convert integer part
-                    let mut nb = String::new();
-                    while ipart != 0 {
-                        nb.insert(0, DIGITS[(ipart % base) as usize] as char);
-                        ipart /= base;
-                    }
+            Ok(labels)
+        }
 
-                    // handle negatives and zero (we must do this
-                    // before converting float part)
-                    if neg { nb.insert(0, '-'); }
-                    if nb.is_empty() { nb.push('0'); }
-
-                    // convert float part
-                    if fpart > EPSILON {
-                        nb.push('.');
-                        for _ in 0..PRECISION {
-                            if fpart <= EPSILON { break; }
-                            fpart *= base as f64;
-                            let digit = fpart.floor();
-                            nb.push(DIGITS[digit as usize] as char);
-                            fpart -= digit;
+        /// Where the chunk containing statement `i` ends.
+        fn chunk_end(&self, i: usize) -> usize {
+            let chunk = self.chunk_ends.partition_point(|&end| end <= i);
+            self.chunk_ends.get(chunk).cloned().unwrap_or(self.program.len())
+        }
+
+        /// Makes sure every label named directly by `goto`, `map` and
+        /// friends exists, so typos are caught before anything runs.
+        fn check_labels(&self, node: &Node, labels: &HashMap<String, usize>)
+                -> Result<(), MacaroniError> {
+            if let NodeKind::Call { ref op, ref args } = node.kind {
+                if let Some(i) = self.ops[op].info.label_arg() {
+                    if let NodeKind::VarRef(ref name) = args[i].kind {
+                        if !labels.contains_key(name) {
+                            return Err(MacaroniError::UnknownLabel {
+                                op: op.clone(), label: name.clone(),
+                                span: args[i].span
+                            });
                         }
                     }
+                }
+                for arg in args { self.check_labels(arg, labels)?; }
+            }
+            Ok(())
+        }
 
-                    Some(Variable::new_arr(Macaroni::string_to_arr(&nb)))
-                }, Val::Arr(_) => panic!("tobase called with Arr") }
-            }, Val::Arr(_) => panic!("tobase called with Arr") }
+        /// Position of the operator currently being applied.
+        fn span(&self) -> Span {
+            self.states[0].op
         }
 
-        fn sort(&mut self, args: &[Variable]) -> Option<Variable> {
-            let mut arr = match args[0].val {
-                Val::Arr(ref a) => a,
-                Val::Num(_) => panic!("map called with Num")
-            }.clone();
-            let lbl = match args[1].var {
+        fn num(&self, op: &'static str, v: &Variable)
+                -> Result<f64, MacaroniError> {
+            match v.val {
+                Val::Num(n) => Ok(n),
+                Val::Arr(_) => Err(MacaroniError::WrongType {
+                    op: op.to_string(), want: Kind::Num, val: v.val.clone(),
+                    span: self.span()
+                })
+            }
+        }
+
+        fn arr<'a>(&self, op: &'static str, v: &'a Variable)
+                -> Result<&'a Vec<Val>, MacaroniError> {
+            match v.val {
+                Val::Arr(ref a) => Ok(a),
+                Val::Num(_) => Err(MacaroniError::WrongType {
+                    op: op.to_string(), want: Kind::Arr, val: v.val.clone(),
+                    span: self.span()
+                })
+            }
+        }
+
+        fn bad_value(&self, op: &'static str, val: &Val, msg: &'static str)
+                -> MacaroniError {
+            MacaroniError::BadValue { op, val: val.clone(), msg, span: self.span() }
+        }
+
+        /// Looks up the label named by `v`, for operators that take one.
+        fn label(&self, op: &'static str, v: &Variable)
+                -> Result<usize, MacaroniError> {
+            let name = match v.var {
                 Some(ref x) => x,
-                None => panic!("map called without label")
+                None => return Err(MacaroniError::NotAName {
+                    op: op.to_string(), val: v.val.clone(), span: self.span()
+                })
             };
-            let lbl_idx = self.find_label(lbl).expect(&format!(""));
-            arr.sort_by(|a, b| {
-                self.vars.insert("_".to_string(),
-                    Val::Arr(vec![a.clone(), b.clone()]));
-                self.run_tokens(lbl_idx);
-                // there's no way to unset a variable, so we can unwrap
-                match self.vars.get("_").unwrap() {
-                    &Val::Num(n) => n.partial_cmp(&0f64).unwrap(),
-                    &Val::Arr(_) => panic!("sort predicate returned Arr")
+            self.labels.get(name).cloned().ok_or_else(||
+                MacaroniError::UnknownLabel {
+                    op: op.to_string(), label: name.clone(), span: self.span()
+                })
+        }
+
+        fn add(&mut self, args: &[Variable]) -> OpResult {
+            Ok(Some(Variable::new_num(
+                self.num("add", &args[0])? + self.num("add", &args[1])?)))
+        }
+
+        fn multiply(&mut self, args: &[Variable]) -> OpResult {
+            Ok(Some(Variable::new_num(self.num("multiply", &args[0])? *
+                                      self.num("multiply", &args[1])?)))
+        }
+
+        fn floor(&mut self, args: &[Variable]) -> OpResult {
+            Ok(Some(Variable::new_num(self.num("floor", &args[0])?.floor())))
+        }
+
+        fn pow(&mut self, args: &[Variable]) -> OpResult {
+            Ok(Some(Variable::new_num(
+                self.num("pow", &args[0])?.powf(self.num("pow", &args[1])?))))
+        }
+
+        fn tobase(&mut self, args: &[Variable]) -> OpResult {
+            let (n, m) = (self.num("tobase", &args[0])?,
+                          self.num("tobase", &args[1])?);
+            if !n.is_finite() {
+                return Err(self.bad_value("tobase", &args[0].val,
+                    "non-finite number"));
+            }
+            if m.fract() != 0f64 || !(2f64..=DIGITS.len() as f64).contains(&m) {
+                return Err(self.bad_value("tobase", &args[1].val,
+                    "base outside 2..36"));
+            }
+            let (neg, n) = (n < 0f64, n.abs());
+            let (base, mut ipart, mut fpart) =
+                (m as u64, n.floor() as u64, n - n.floor());
+
+            // convert integer part
+            let mut nb = String::new();
+            while ipart != 0 {
+                nb.insert(0, DIGITS[(ipart % base) as usize] as char);
+                ipart /= base;
+            }
+
+            // handle negatives and zero (we must do this
+            // before converting float part)
+            if nb.is_empty() { nb.push('0'); }
+            if neg { nb.insert(0, '-'); }
+
+            // convert float part
+            if fpart > EPSILON {
+                nb.push('.');
+                for _ in 0..PRECISION {
+                    if fpart <= EPSILON { break; }
+                    fpart *= base as f64;
+                    let digit = fpart.floor();
+                    nb.push(DIGITS[digit as usize] as char);
+                    fpart -= digit;
                 }
-            });
-            Some(Variable::new_arr(arr))
-        }
-
-        fn concat(&mut self, args: &[Variable]) -> Option<Variable> {
-            let mut arr = match args[0].val {
-                Val::Arr(ref a) => a,
-                Val::Num(_) => panic!("concat called with Num")
-            }.clone();
-            arr.extend(match args[1].val {
-                Val::Arr(ref a) => a,
-                Val::Num(_) => panic!("concat called with Num")
-            }.clone().into_iter());
-            Some(Variable::new_arr(arr))
-        }
-
-        fn each(&mut self, args: &[Variable]) -> Option<Variable> {
-            let a = match args[0].val {
-                Val::Arr(ref a) => a,
-                Val::Num(_) => panic!("each called with Num")
-            }.clone();
-            let (neg, n) = match args[1].val {
-                Val::Num(n) => (n < 0f64, if n < 0f64 { -n } else { n } as usize),
-                Val::Arr(_) => panic!("each called with Arr")
+            }
+
+            Ok(Some(Variable::new_arr(Macaroni::string_to_arr(&nb))))
+        }
+
+        fn sort(&mut self, args: &[Variable]) -> OpResult {
+            let arr = self.arr("sort", &args[0])?.clone();
+            let lbl_idx = self.label("sort", &args[1])?;
+            Ok(Some(Variable::new_arr(self.merge_sort(arr, lbl_idx)?)))
+        }
+
+        /// Sorts `arr` with the label at `lbl_idx` as the comparison. This
+        /// is written out by hand since the label might not give a
+        /// consistent order, which `slice::sort_by` is allowed to panic on.
+        fn merge_sort(&mut self, mut arr: Vec<Val>, lbl_idx: usize)
+                -> Result<Vec<Val>, MacaroniError> {
+            if arr.len() <= 1 { return Ok(arr); }
+            let right = arr.split_off(arr.len() / 2);
+            let left = self.merge_sort(arr, lbl_idx)?;
+            let right = self.merge_sort(right, lbl_idx)?;
+            let mut merged = Vec::with_capacity(left.len() + right.len());
+            let (mut left, mut right) =
+                (left.into_iter().peekable(), right.into_iter().peekable());
+            while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+                // take from the left unless it's greater, to keep it stable
+                let next = if self.compare(a, b, lbl_idx)? == Ordering::Greater
+                    { right.next() } else { left.next() };
+                merged.extend(next);
+            }
+            merged.extend(left);
+            merged.extend(right);
+            Ok(merged)
+        }
+
+        fn compare(&mut self, a: &Val, b: &Val, lbl_idx: usize)
+                -> Result<Ordering, MacaroniError> {
+            self.set_var("_".to_string(), Val::Arr(vec![a.clone(), b.clone()]))?;
+            self.callback("sort", lbl_idx)?;
+            // there's no way to unset a variable, so we can unwrap
+            match *self.vars.get("_").unwrap() {
+                Val::Num(n) => Ok(n.partial_cmp(&0f64).unwrap_or(Ordering::Equal)),
+                ref x @ Val::Arr(_) => Err(MacaroniError::WrongType {
+                    op: "sort".to_string(), want: Kind::Num, val: x.clone(),
+                    span: self.span()
+                })
+            }
+        }
+
+        fn concat(&mut self, args: &[Variable]) -> OpResult {
+            let mut arr = self.arr("concat", &args[0])?.clone();
+            arr.extend(self.arr("concat", &args[1])?.iter().cloned());
+            Ok(Some(Variable::new_arr(arr)))
+        }
+
+        fn each(&mut self, args: &[Variable]) -> OpResult {
+            let a = self.arr("each", &args[0])?;
+            let (neg, n) = match self.num("each", &args[1])? {
+                n if n < 0f64 => (true, -n as usize),
+                n => (false, n as usize)
             };
+            if neg && n == 0 {
+                return Err(self.bad_value("each", &args[1].val,
+                    "slice size of 0"));
+            }
             let mut arr = Vec::<Val>::new();
             if neg {
                 // full subarrays
@@ -411,140 +1203,108 @@ pub mod macaroni {
                 if a.len() % n != 0 {
                     arr.push(Val::Arr(Vec::from(&a[a.len() / n * n..a.len()])));
                 }
-            } else {
-                if a.len() >= n {
-                    for i in 0..a.len() - n + 1 {
-                        arr.push(Val::Arr(Vec::from(&a[i..i + n])));
-                    }
+            } else if a.len() >= n {
+                // overlapping windows can be much bigger than `a`, so check
+                // before building them
+                self.reserve((a.len() - n + 1).saturating_mul(n)
+                    .saturating_mul(mem::size_of::<Val>()))?;
+                for i in 0..a.len() - n + 1 {
+                    arr.push(Val::Arr(Vec::from(&a[i..i + n])));
                 }
             }
-            Some(Variable::new_arr(arr))
+            Ok(Some(Variable::new_arr(arr)))
         }
 
-        fn map(&mut self, args: &[Variable]) -> Option<Variable> {
-            let arr = match args[0].val {
-                Val::Arr(ref a) => a,
-                Val::Num(_) => panic!("map called with Num")
-            }.clone();
-            let lbl = match args[1].var {
-                Some(ref x) => x,
-                None => panic!("map called without label")
-            };
-            let lbl_idx = self.find_label(lbl).expect(&format!(""));
-            Some(Variable::new_arr(arr.into_iter().map(|x| {
-                self.vars.insert("_".to_string(), x.clone());
-                self.run_tokens(lbl_idx);
-                self.vars.get("_").unwrap().clone()
-            }).collect()))
-        }
-
-        fn index(&mut self, args: &[Variable]) -> Option<Variable> {
-            let arr = match args[0].val {
-                Val::Arr(ref a) => a,
-                Val::Num(_) => panic!("index called with Num")
-            }.clone();
-            let lbl = match args[1].var {
-                Some(ref x) => x,
-                None => panic!("index called without label")
-            };
-            let lbl_idx = self.find_label(lbl).expect(&format!(""));
-            Some(Variable::new_arr(arr.into_iter().enumerate().filter(|&(_, ref x)| {
-                self.vars.insert("_".to_string(), x.clone());
-                self.run_tokens(lbl_idx);
-                match self.vars.get("_").unwrap() {
-                    &Val::Arr(ref a) => !a.is_empty(),
-                    &Val::Num(n) => n != 0f64
-                }
-            }).map(|(i, _)| Val::Num(i as f64)).collect()))
-        }
-
-        fn slice(&mut self, args: &[Variable]) -> Option<Variable> {
-            Some(Variable::new_arr(match args[0].val {
-                Val::Arr(ref a) => {
-                    let (step, rev) = match args[3].val {
-                        Val::Num(n) => {
-                            if n > 0f64 {
-                                (n as usize, false)
-                            } else {
-                                (-n as usize, true)
-                            }
-                        },
-                        Val::Arr(_) => panic!("slice called with Arr")
-                    };
-                    let (mut idx, to) = (
-                        match args[if rev { 2 } else { 1 }].val {
-                            Val::Num(n) => n as usize,
-                            Val::Arr(_) => panic!("slice called with Arr")
-                        },
-                        match args[if rev { 1 } else { 2 }].val {
-                            Val::Num(n) => n as usize,
-                            Val::Arr(_) => panic!("slice called with Arr")
-                        }
-                    );
-                    if rev {
-                        if idx > a.len() { idx = a.len(); }
-                        else if idx > 0 { idx -= 1; }
-                        else { return Some(Variable::new_arr(vec![])); }
-                    }
-                    let mut new_arr = Vec::<Val>::new();
-                    while if rev { idx >= to } else { idx < to } {
-                        if idx < a.len() {
-                            new_arr.push(a[idx].clone());
-                        } else {
-                            if !rev { break; }
-                        }
-                        if rev {
-                            if step > idx { break; }
-                            idx -= step;
-                        } else { idx += step; }
-                    }
-                    new_arr
-                }
-                Val::Num(_) => panic!("slice called with Num")
-            }))
+        fn map(&mut self, args: &[Variable]) -> OpResult {
+            let arr = self.arr("map", &args[0])?.clone();
+            let lbl_idx = self.label("map", &args[1])?;
+            let mut mapped = Vec::with_capacity(arr.len());
+            // callbacks can leave anything in `_`, so keep count as it grows
+            let mut size = 0;
+            for x in arr {
+                self.set_var("_".to_string(), x)?;
+                self.callback("map", lbl_idx)?;
+                let val = self.vars["_"].clone();
+                size += Macaroni::size(&val);
+                self.reserve(size)?;
+                mapped.push(val);
+            }
+            Ok(Some(Variable::new_arr(mapped)))
         }
 
-        fn length(&mut self, args: &[Variable]) -> Option<Variable> {
-            Some(Variable::new_num(match args[0].val {
-                Val::Arr(ref a) => a.len() as f64,
-                Val::Num(_) => panic!("length called with Num")
-            }))
+        fn index(&mut self, args: &[Variable]) -> OpResult {
+            let arr = self.arr("index", &args[0])?.clone();
+            let lbl_idx = self.label("index", &args[1])?;
+            let mut indices = vec![];
+            for (i, x) in arr.into_iter().enumerate() {
+                self.set_var("_".to_string(), x)?;
+                self.callback("index", lbl_idx)?;
+                if match self.vars["_"] {
+                    Val::Arr(ref a) => !a.is_empty(),
+                    Val::Num(n) => n != 0f64
+                } {
+                    self.reserve((indices.len() + 1) * mem::size_of::<Val>())?;
+                    indices.push(Val::Num(i as f64));
+                }
+            }
+            Ok(Some(Variable::new_arr(indices)))
         }
 
-        fn transpose(&mut self, args: &[Variable]) -> Option<Variable> {
-            let arr = match args[0].val {
-                Val::Arr(ref a) => a.clone(),
-                Val::Num(_) => panic!("transpose called with Num")
+        fn slice(&mut self, args: &[Variable]) -> OpResult {
+            let a = self.arr("slice", &args[0])?;
+            let (step, rev) = match self.num("slice", &args[3])? {
+                n if n > 0f64 => (n as usize, false),
+                n => (-n as usize, true)
             };
-            if let Some(max_len) = arr.iter().map(|x|
-                match x {
-                    &Val::Arr(ref a) => a.len(),
-                    &Val::Num(_) => panic!("transpose called with non-2D Arr")
+            if step == 0 {
+                return Err(self.bad_value("slice", &args[3].val, "step of 0"));
+            }
+            let (mut idx, to) = (
+                self.num("slice", &args[if rev { 2 } else { 1 }])? as usize,
+                self.num("slice", &args[if rev { 1 } else { 2 }])? as usize
+            );
+            if rev {
+                if idx > a.len() { idx = a.len(); }
+                else if idx > 0 { idx -= 1; }
+                else { return Ok(Some(Variable::new_arr(vec![]))); }
+            }
+            let mut new_arr = Vec::<Val>::new();
+            while if rev { idx >= to } else { idx < to } {
+                if idx < a.len() {
+                    new_arr.push(a[idx].clone());
+                } else if !rev { break; }
+                if rev {
+                    if step > idx { break; }
+                    idx -= step;
+                } else { idx += step; }
+            }
+            Ok(Some(Variable::new_arr(new_arr)))
+        }
+
+        fn length(&mut self, args: &[Variable]) -> OpResult {
+            Ok(Some(Variable::new_num(self.arr("length", &args[0])?.len() as f64)))
+        }
+
+        fn transpose(&mut self, args: &[Variable]) -> OpResult {
+            let mut rows = vec![];
+            for x in self.arr("transpose", &args[0])? {
+                match *x {
+                    Val::Arr(ref a) => rows.push(a),
+                    Val::Num(_) => return Err(self.bad_value("transpose",
+                        &args[0].val, "non-2D Arr"))
                 }
-            ).max() {
-                Some(Variable::new_arr((0..max_len).map(|i|
-                    Val::Arr(arr.iter().filter_map(|x|
-                        match x {
-                            &Val::Arr(ref a) => a,
-                            &Val::Num(_) => unreachable!()
-                        }.get(i).map(|val| val.clone())
-                    ).collect())
-                ).collect()))
-            } else {
-                Some(Variable::new_arr(vec![]))
             }
+            let max_len = rows.iter().map(|a| a.len()).max().unwrap_or(0);
+            Ok(Some(Variable::new_arr((0..max_len).map(|i|
+                Val::Arr(rows.iter().filter_map(|a| a.get(i).cloned())
+                    .collect())
+            ).collect())))
         }
 
-        fn flatten(&mut self, args: &[Variable]) -> Option<Variable> {
-            let mut arr = match args[0].val {
-                Val::Arr(ref a) => a.clone(),
-                Val::Num(_) => panic!("flatten called with Num")
-            };
-            let n = match args[1].val {
-                Val::Num(n) => n as usize,
-                Val::Arr(_) => panic!("flatten called with Arr")
-            };
-            for _ in 0..if n == 0 { usize::max_value() } else { n } {
+        fn flatten(&mut self, args: &[Variable]) -> OpResult {
+            let mut arr = self.arr("flatten", &args[0])?.clone();
+            let n = self.num("flatten", &args[1])? as usize;
+            for _ in 0..if n == 0 { usize::MAX } else { n } {
                 let mut tmp = Vec::<Val>::new();
                 let mut found_arr = false;
                 for val in arr {
@@ -556,128 +1316,367 @@ pub mod macaroni {
                 arr = tmp;
                 if !found_arr { break }
             }
-            Some(Variable::new_arr(arr))
-        }
-
-        fn frombase(&mut self, args: &[Variable]) -> Option<Variable> {
-            match args[0].val { Val::Arr(ref s) => {
-                match args[1].val { Val::Num(n) => {
-                    let (base, mut nb) = (n, Macaroni::arr_to_string(&s));
-
-                    // handle negatives and decimals
-                    let neg = nb.starts_with("-");
-                    if neg { nb.remove(0); }
-
-                    let sub_pos = if let Some(dot_pos) = nb.find('.') {
-                        nb.remove(dot_pos);
-                        dot_pos - 1
-                    } else {
-                        nb.len() - 1
-                    } as i32;
-
-                    // convert cleaned-up string
-                    let mut n = 0f64;
-                    for i in (0..nb.len()).rev() {
-                        let c = nb.chars().nth(i).unwrap().to_uppercase()
-                            .next().unwrap() as u8;
-                        let digit = DIGITS.iter().position(|d| *d == c)
-                            .expect(&format!("unrecognized digit {}", c)) as f64;
-                        n += digit * base.powi(sub_pos - i as i32) as f64;
-                    }
+            Ok(Some(Variable::new_arr(arr)))
+        }
 
-                    Some(Variable::new_num(n))
-                }, Val::Arr(_) => panic!("frombase called with Arr") }
-            }, Val::Num(_) => panic!("frombase called with Num") }
+        fn frombase(&mut self, args: &[Variable]) -> OpResult {
+            let s = self.arr("frombase", &args[0])?;
+            let base = self.num("frombase", &args[1])?;
+            if base.fract() != 0f64 ||
+                    !(2f64..=DIGITS.len() as f64).contains(&base) {
+                return Err(self.bad_value("frombase", &args[1].val,
+                    "base outside 2..36"));
+            }
+            let mut nb = self.arr_to_string("frombase", s)?;
+
+            // handle negatives and decimals
+            let neg = nb.starts_with('-');
+            if neg { nb.remove(0); }
+
+            let sub_pos = if let Some(dot_pos) = nb.find('.') {
+                nb.remove(dot_pos);
+                dot_pos as i32 - 1
+            } else {
+                nb.len() as i32 - 1
+            };
+
+            // convert cleaned-up string
+            let mut n = 0f64;
+            for (i, c) in nb.char_indices().rev() {
+                let c = c.to_ascii_uppercase();
+                let digit = match DIGITS.iter().position(|&d| d as char == c) {
+                    Some(d) if (d as f64) < base => d as f64,
+                    Some(_) => return Err(self.bad_value("frombase",
+                        &args[0].val, "digit too big for the base")),
+                    None => return Err(self.bad_value("frombase", &args[0].val,
+                        "unrecognized digit"))
+                };
+                n += digit * base.powi(sub_pos - i as i32);
+            }
+
+            Ok(Some(Variable::new_num(if neg { -n } else { n })))
         }
 
-        fn wrap(&mut self, args: &[Variable]) -> Option<Variable> {
-            let ref x = args[0];
-            Some(Variable::new_arr(vec![x.val.clone()]))
+        fn wrap(&mut self, args: &[Variable]) -> OpResult {
+            Ok(Some(Variable::new_arr(vec![args[0].val.clone()])))
         }
 
-        fn unwrap(&mut self, args: &[Variable]) -> Option<Variable> {
-            match args[0].val {
-                Val::Arr(ref a) => {
-                    if a.len() == 1 {
-                        Some(Variable { val: a[0].clone(), var: None })
-                    } else {
-                        panic!("unwrap called with Arr of length != 1")
-                    }
-                },
-                Val::Num(_) => panic!("unwrap called with Num")
+        fn unwrap(&mut self, args: &[Variable]) -> OpResult {
+            let a = self.arr("unwrap", &args[0])?;
+            if a.len() == 1 {
+                Ok(Some(Variable { val: a[0].clone(), var: None }))
+            } else {
+                Err(self.bad_value("unwrap", &args[0].val,
+                    "Arr of length != 1"))
             }
         }
 
-        fn print(&mut self, args: &[Variable]) -> Option<Variable> {
-            let ref x = args[0];
-            match x.val {
-                Val::Arr(ref s) => {
-                    let stdout = io::stdout();
-                    stdout.lock().write(s.iter().map(|y|
-                        match y {
-                            &Val::Num(n) => n as u8,
-                            &Val::Arr(_) => panic!("print called with non-string"),
-                        }).collect::<Vec<u8>>().as_slice()).unwrap();
-                },
-                Val::Num(_) => panic!("print called with Num")
-            };
-            None
+        fn print(&mut self, args: &[Variable]) -> OpResult {
+            let s = self.arr_to_string("print", self.arr("print", &args[0])?)?;
+            self.output.write_all(s.as_bytes())
+                .and_then(|_| self.output.flush()).map_err(|err|
+                    MacaroniError::Io { op: "print", err, span: self.span() })?;
+            Ok(None)
         }
 
-        fn read(&mut self, _: &[Variable]) -> Option<Variable> {
+        fn read(&mut self, _: &[Variable]) -> OpResult {
             let mut line = String::new();
-            io::stdin().read_line(&mut line).unwrap();
-            Some(Variable::new_arr(Macaroni::string_to_arr(&line)))
+            // every byte becomes at least one value, so don't read more than
+            // could possibly fit
+            let max = self.limits.memory.map_or(u64::MAX, |n|
+                (n.saturating_sub(self.memory) / mem::size_of::<Val>()) as u64);
+            let read = match self.input {
+                Some(ref mut input) =>
+                    input.take(max.saturating_add(1)).read_line(&mut line),
+                None => io::stdin().lock().take(max.saturating_add(1))
+                    .read_line(&mut line)
+            };
+            read.map_err(|err|
+                MacaroniError::Io { op: "read", err, span: self.span() })?;
+            self.reserve(line.len().saturating_mul(mem::size_of::<Val>()))?;
+            Ok(Some(Variable::new_arr(Macaroni::string_to_arr(&line))))
         }
 
-        fn rand(&mut self, _: &[Variable]) -> Option<Variable> {
-            Some(Variable::new_num(rand::random()))
+        fn rand(&mut self, _: &[Variable]) -> OpResult {
+            Ok(Some(Variable::new_num(rand::random())))
         }
 
-        fn time(&mut self, _: &[Variable]) -> Option<Variable> {
+        fn time(&mut self, _: &[Variable]) -> OpResult {
             let t = time::get_time();
-            Some(Variable::new_num((t.sec as f64) + (t.nsec as f64) /
-                                                     1000000000f64))
+            Ok(Some(Variable::new_num((t.sec as f64) + (t.nsec as f64) /
+                                                         1000000000f64)))
         }
 
-        fn set(&mut self, args: &[Variable]) -> Option<Variable> {
-            self.vars.insert(args[0].clone().var.expect("cannot set a literal"),
-                args[1].clone().val);
-            Some(Variable {
-                val: args[1].clone().val, var: args[0].clone().var
-            })
+        fn set(&mut self, args: &[Variable]) -> OpResult {
+            let name = match args[0].var {
+                Some(ref x) => x.clone(),
+                None => return Err(MacaroniError::NotAName {
+                    op: "set".to_string(), val: args[0].val.clone(), span: self.span()
+                })
+            };
+            self.set_var(name.clone(), args[1].val.clone())?;
+            Ok(Some(Variable { val: args[1].val.clone(), var: Some(name) }))
         }
 
-        fn goto(&mut self, args: &[Variable]) -> Option<Variable> {
-            match args[0].var {
-                Some(ref label) => {
-                    let old_i = self.states[0].i;
-                    self.states[0].call_stack.push(old_i);
-                    self.states[0].i = self.find_label(label)
-                        .expect(&format!("goto unknown label"));
-                },
-                None => panic!("cannot goto literal")
-            }
-            None
+        fn goto(&mut self, args: &[Variable]) -> OpResult {
+            let target = self.label("goto", &args[0])?;
+            let ret = (self.states[0].i, self.states[0].end);
+            self.states[0].call_stack.push(ret);
+            self.states[0].i = target;
+            self.states[0].end = self.chunk_end(target);
+            let (span, to) = (self.span(), self.program[target].span);
+            let name = args[0].var.clone().unwrap();
+            self.notify(|hook, mac| hook.on_goto(mac, &name, span, to));
+            Ok(None)
         }
 
-        fn return_(&mut self, _: &[Variable]) -> Option<Variable> {
-            self.states[0].i = match self.states[0].call_stack.pop() {
-                Some(i) => i,
-                None => self.program.len()
-            };
-            None
+        fn return_(&mut self, _: &[Variable]) -> OpResult {
+            let end = self.states[0].end;
+            let ret = self.states[0].call_stack.pop();
+            let (i, end) = ret.unwrap_or((end, end));
+            self.states[0].i = i;
+            self.states[0].end = end;
+            let span = self.span();
+            let to = ret.map(|(i, _)| self.program[i - 1].span);
+            self.notify(|hook, mac| hook.on_return(mac, span, to));
+            Ok(None)
+        }
+
+        fn arr_to_string(&self, op: &'static str, arr: &[Val])
+                -> Result<String, MacaroniError> {
+            arr.iter().map(|x| match *x {
+                Val::Num(n) => Macaroni::code_point(n).ok_or_else(||
+                    MacaroniError::NotACodePoint { op, n, span: self.span() }),
+                Val::Arr(_) => Err(self.bad_value(op, &Val::Arr(arr.to_vec()),
+                    "non-string"))
+            }).collect()
+        }
+
+        fn code_point(n: f64) -> Option<char> {
+            if n.fract() == 0f64 && (0f64..=u32::MAX as f64).contains(&n) {
+                ::std::char::from_u32(n as u32)
+            } else { None }
+        }
+
+        pub(crate) fn string_to_arr(str: &str) -> Vec<Val> {
+            str.chars().map(|c| Val::Num(c as u32 as f64)).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use macaroni::*;
+    use std::time::Duration;
+
+    fn limited(limits: Limits, code: &str) -> Limit {
+        let (mut mac, _) = Macaroni::in_memory("");
+        mac.set_limits(limits);
+        match mac.run(code.to_string()) {
+            Err(MacaroniError::LimitExceeded { limit, .. }) => limit,
+            Err(e) => panic!("{:?} failed with {}", code, e),
+            Ok(val) => panic!("{:?} finished with {:?}", code, val)
         }
+    }
+
+    /// What `code` evaluates to, as `Debug` shows it.
+    fn eval(mac: &mut Macaroni, code: &str) -> String {
+        match mac.run(code.to_string()) {
+            Ok(Some(val)) => format!("{:?}", val),
+            other => panic!("{:?} gave {:?}", code, other)
+        }
+    }
+
+    const FOREVER: &str = "set i 0 label l set i add i 1 goto l";
 
-        fn arr_to_string(arr: &Vec<Val>) -> String {
-            arr.iter().map(|x| match x {
-                &Val::Num(n) => n,
-                &Val::Arr(_) => panic!("arr_to_string called with non-string")
-            } as u8 as char).collect::<String>()
+    #[test]
+    fn steps() {
+        let limits = Limits { steps: Some(100), ..Limits::default() };
+        assert_eq!(limited(limits, FOREVER), Limit::Steps(100));
+        // the count starts again on each run
+        let (mut mac, _) = Macaroni::in_memory("");
+        mac.set_limits(limits);
+        for _ in 0..3 {
+            mac.run("set i add i 1 set i add i 1".into()).unwrap();
         }
+    }
+
+    #[test]
+    fn time() {
+        let limits = Limits {
+            time: Some(Duration::from_millis(10)), ..Limits::default()
+        };
+        assert!(matches!(limited(limits, FOREVER), Limit::Time(_)));
+    }
+
+    #[test]
+    fn depth() {
+        let limits = Limits { depth: Some(10), ..Limits::default() };
+        assert_eq!(limited(limits, "map [1] f return label f set _ map [1] f"),
+                   Limit::Depth(10));
+        let (mut mac, _) = Macaroni::in_memory("");
+        mac.set_limits(limits);
+        assert!(mac.run("map [1] f return label f set _ 2".into()).is_ok());
+        // runaway recursion is stopped even without asking
+        let (mut mac, _) = Macaroni::in_memory("");
+        assert!(matches!(mac.run("map [1] f return label f set _ map [1] f"
+                                 .into()),
+            Err(MacaroniError::LimitExceeded {
+                limit: Limit::Depth(DEFAULT_DEPTH), ..
+            })));
+    }
 
-        fn string_to_arr(str: &String) -> Vec<Val> {
-            str.chars().map(|c| Val::Num(c as u8 as f64)).collect()
+    #[test]
+    fn memory() {
+        let limits = Limits { memory: Some(10_000), ..Limits::default() };
+        assert_eq!(limited(limits, "label l set x wrap x goto l"),
+                   Limit::Memory(10_000));
+        // what `map` builds counts before it's stored anywhere
+        let code = format!("set x map [0 0 0 0 0 0 0 0] f return \
+                            label f set _ [{}]", vec!["0"; 64].join(" "));
+        assert_eq!(limited(limits, &code), Limit::Memory(10_000));
+        // and so does what `read` reads
+        let (mut mac, _) = Macaroni::in_memory(&"a".repeat(10_000));
+        mac.set_limits(limits);
+        assert!(matches!(mac.run("read".into()),
+            Err(MacaroniError::LimitExceeded { limit: Limit::Memory(_), .. })));
+    }
+
+    #[test]
+    fn print_and_read() {
+        let (mut mac, out) = Macaroni::in_memory("hello\nworld\n");
+        mac.run("print read print \"!\" print read".into()).unwrap();
+        assert_eq!(out.contents(), "hello\n!world\n");
+        // nothing left to read
+        match mac.run("length read".into()) {
+            Ok(Some(Val::Num(n))) => assert_eq!(n, 0.0),
+            other => panic!("read at the end gave {:?}", other)
+        }
+        assert_eq!(out.contents(), "hello\n!world\n");
+    }
+
+    #[test]
+    fn register_op() {
+        let (mut mac, _) = Macaroni::in_memory("");
+        mac.register_op("double", 1, |args| match args[0] {
+            Val::Num(n) => Ok(Some(Val::Num(n * 2.0))),
+            Val::Arr(_) => Err("can't double an array".to_string())
+        });
+        assert_eq!(eval(&mut mac, "add double 3 1"), "7");
+        match mac.run("double [1]".into()) {
+            Err(MacaroniError::OpFailed { ref op, ref msg, span }) => {
+                assert_eq!((&op[..], &msg[..]),
+                           ("double", "can't double an array"));
+                assert_eq!(span.col, 1);
+            },
+            other => panic!("double [1] gave {:?}", other)
+        }
+        // built-ins can be replaced, arity and all
+        mac.register_op("add", 3, |args| Ok(Some(args[2].clone())));
+        assert_eq!(eval(&mut mac, "add 1 2 3"), "3");
+        assert_eq!(mac.op("add").unwrap().arity(), 3);
+    }
+
+    #[test]
+    fn register_op_with() {
+        let (mut mac, _) = Macaroni::in_memory("");
+        let info = OpInfo::new("first", "a -> n", "the first element");
+        mac.register_op_with(info, |args| match args[0] {
+            Val::Arr(ref a) => Ok(a.first().cloned()),
+            Val::Num(_) => unreachable!("arguments are checked first")
+        });
+        assert_eq!(eval(&mut mac, "first [5 6]"), "5");
+        assert!(matches!(mac.run("first 1".into()),
+            Err(MacaroniError::WrongType { want: Kind::Arr, .. })));
+        let op = mac.op("first").unwrap();
+        assert_eq!((op.signature(), &op.help[..]),
+                   ("a -> n".to_string(), "the first element"));
+        assert!(mac.ops().iter().any(|op| op.name == "first"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn register_op_bad_name() {
+        Macaroni::new().register_op("1x", 0, |_| Ok(None));
+    }
+
+    #[test]
+    fn chunks() {
+        let (mut mac, _) = Macaroni::in_memory("");
+        mac.run("set x 1 return label inc set _ add _ x return".into())
+            .unwrap();
+        // labels and variables carry over
+        assert_eq!(eval(&mut mac, "map [1 2] inc"), "[2, 3]");
+        assert_eq!(mac.labels(), ["inc"]);
+        // a label can be redefined by a later chunk
+        mac.run("return label inc set _ 0 return".into()).unwrap();
+        assert_eq!(eval(&mut mac, "map [1 2] inc"), "[0, 0]");
+        // falling off the end of a chunk doesn't run into the next one
+        mac.run("set z 1 return label tail set z 2".into()).unwrap();
+        mac.run("set z 9".into()).unwrap();
+        mac.run("goto tail".into()).unwrap();
+        assert_eq!(eval(&mut mac, "z"), "2");
+    }
+
+    #[test]
+    fn chunk_spans() {
+        let (mut mac, _) = Macaroni::in_memory("");
+        let first = "return label f set _ length _ return";
+        mac.run(first.to_string()).unwrap();
+        let e = mac.run("set aaaaaaaaaaaaaaaaaaaaaa map [1 2] f".into())
+            .unwrap_err();
+        let span = e.span();
+        assert_eq!((span.line, span.col), (1, 22));
+        assert_eq!(mac.source(span), Some(first));
+        // errors before anything runs are in the code just given
+        let e = mac.run("length".into()).unwrap_err();
+        assert_eq!(mac.source(e.span()), None);
+        mac.reset();
+        assert_eq!(mac.source(span), None);
+    }
+
+    #[test]
+    fn display() {
+        let (mut mac, _) = Macaroni::in_memory("");
+        let mut show = |code: &str|
+            mac.run(code.to_string()).unwrap().unwrap().to_string();
+        assert_eq!(show("\"hi\""), "\"hi\"");
+        assert_eq!(show("\"a\\tb\\n\""), "\"a\\tb\\n\"");
+        assert_eq!(show("[\"ab\" \"c\"]"), "[\"ab\" \"c\"]");
+        assert_eq!(show("[1 2.5 -3]"), "[1 2.5 -3]");
+        assert_eq!(show("nan"), "nan");
+        assert_eq!(show("[]"), "[]");
+        // control characters and whitespace alone aren't strings
+        assert_eq!(show("[9 10]"), "[9 10]");
+        assert_eq!(show("[[11 12] [13]]"), "[[11 12] [13]]");
+        assert_eq!(show("\" \""), "[32]");
+        assert_eq!(show("[104 0]"), "[104 0]");
+        assert_eq!(show("[104 1114112]"), "[104 1114112]");
+    }
+
+    #[test]
+    fn frombase() {
+        let (mut mac, _) = Macaroni::in_memory("");
+        assert_eq!(eval(&mut mac, "frombase \"ff\" 16"), "255");
+        assert_eq!(eval(&mut mac, "frombase \"-ff\" 16"), "-255");
+        assert_eq!(eval(&mut mac, "frombase \"z\" 36"), "35");
+        assert_eq!(eval(&mut mac, "frombase \"1.1\" 2"), "1.5");
+        assert_eq!(mac.run("tobase -12.25 10".into()).unwrap().unwrap()
+                   .to_string(), "\"-12.25\"");
+        assert_eq!(eval(&mut mac, "frombase tobase -12.25 2 2"), "-12.25");
+        let base = "base outside 2..36";
+        let digit = "digit too big for the base";
+        for &(code, msg) in &[("frombase \"10\" 100", base),
+                              ("frombase \"10\" 1", base),
+                              ("frombase \"10\" 2.5", base),
+                              ("frombase \"z\" 10", digit),
+                              ("frombase \"12\" 2", digit),
+                              ("frombase \"1?\" 10", "unrecognized digit")] {
+            match mac.run(code.to_string()) {
+                Err(MacaroniError::BadValue { op: "frombase", msg: m, .. }) =>
+                    assert_eq!(m, msg, "{}", code),
+                other => panic!("{:?} gave {:?}", code, other)
+            }
         }
     }
 }
@@ -0,0 +1,228 @@
+//! Warnings about code that's legal but probably not what was meant. Each
+//! kind of warning has a code, so they can be looked up and filtered:
+//!
+//! - `W001`: a label that nothing jumps to or calls back to
+//! - `W002`: a jump or callback to a label that doesn't exist
+//! - `W003`: a variable read before anything sets it, so it's always 0 there
+//! - `W004`: a label that `map` or `sort` calls back to that never sets `_`
+//! - `W005`: code after a `return` that nothing can reach
+
+use std::collections::{HashMap, HashSet};
+
+use checker;
+use lexer::Span;
+use macaroni::{self, Kind, OpInfo};
+use parser::{Node, NodeKind};
+
+#[derive(Clone, Debug)]
+pub struct Warning {
+    pub code: &'static str,
+    pub msg: String,
+    pub span: Span
+}
+
+impl Warning {
+    /// Formats the warning like `MacaroniError::render` does an error.
+    pub fn render(&self, name: &str, source: &str) -> String {
+        macaroni::render(self.span, &format!("warning[{}]: {}", self.code,
+                                             self.msg), name, source)
+    }
+}
+
+struct Linter<'a, F: Fn(&str) -> Option<&'a OpInfo>> {
+    op: F,
+    /// Each label, and where it's defined.
+    labels: HashMap<&'a str, Span>,
+    /// Labels that are jumped or called back to.
+    used: HashSet<&'a str>,
+    /// Labels `map` or `sort` call back to, with the first operator doing so;
+    /// `index` often leaves `_` alone on purpose.
+    callbacks: HashMap<&'a str, &'a str>,
+    /// Variables that have been set so far, in the order things run in.
+    set: HashSet<&'a str>,
+    /// Every variable that's `set` somewhere.
+    assigned: HashSet<&'a str>,
+    warnings: Vec<Warning>
+}
+
+impl<'a, F: Fn(&str) -> Option<&'a OpInfo>> Linter<'a, F> {
+    fn warn(&mut self, code: &'static str, span: Span, msg: String) {
+        self.warnings.push(Warning { code, msg, span });
+    }
+
+    /// Looks at `node`, arguments first, just as it would run.
+    fn visit(&mut self, node: &'a Node) {
+        match node.kind {
+            NodeKind::Call { ref op, ref args } => {
+                let info = (self.op)(op).expect("parsed with unknown operator");
+                for (arg, &kind) in args.iter().zip(&info.args) {
+                    match (kind, &arg.kind) {
+                        (Kind::Label, NodeKind::VarRef(name)) =>
+                            self.label_ref(op, name, arg.span),
+                        (Kind::Var, NodeKind::VarRef(_)) => (),
+                        _ => self.visit(arg)
+                    }
+                }
+                if let (true, Some(NodeKind::VarRef(name))) =
+                        (op == "set", args.first().map(|a| &a.kind)) {
+                    self.set.insert(name);
+                }
+                // whatever comes next might be reached from anywhere
+                if op == "return" || info.args.contains(&Kind::Label) {
+                    self.forget();
+                }
+            },
+            NodeKind::VarRef(ref name) => {
+                // `_` is set by whatever calls back, and one warning for
+                // each variable is plenty
+                if name != "_" && self.set.insert(name) {
+                    self.warn("W003", node.span, format!(
+                        "{} is read before it's set, so it's 0 here", name));
+                }
+            },
+            NodeKind::Literal(_) | NodeKind::Label(_) => ()
+        }
+    }
+
+    /// Counts every variable as set, since a jump or callback (or jumping
+    /// back here) could have set any of them.
+    fn forget(&mut self) {
+        self.set.extend(self.assigned.iter().cloned());
+    }
+
+    fn label_ref(&mut self, op: &'a str, name: &'a str, span: Span) {
+        if !self.labels.contains_key(name) {
+            self.warn("W002", span, format!("{} to unknown label {}", op, name));
+        }
+        self.used.insert(name);
+        if op == "map" || op == "sort" {
+            self.callbacks.entry(name).or_insert(op);
+        }
+    }
+}
+
+/// Whether `node` sets `_` or jumps somewhere that might.
+fn sets_underscore(node: &Node) -> bool {
+    match node.kind {
+        NodeKind::Call { ref op, ref args } => {
+            let target = args.first().map(|a| &a.kind);
+            op == "goto" ||
+                (op == "set" &&
+                 matches!(target, Some(NodeKind::VarRef(name)) if name == "_")) ||
+                args.iter().any(sets_underscore)
+        },
+        _ => false
+    }
+}
+
+fn is_return(node: &Node) -> bool {
+    matches!(node.kind, NodeKind::Call { ref op, .. } if op == "return")
+}
+
+/// Lints `program`, which was parsed with the operators `op` describes.
+/// Returns the warnings in the order they appear in the source.
+pub fn lint<'a, F>(program: &'a [Node], op: F) -> Vec<Warning>
+        where F: Fn(&str) -> Option<&'a OpInfo> {
+    let mut linter = Linter {
+        op, labels: HashMap::new(), used: HashSet::new(),
+        callbacks: HashMap::new(), set: HashSet::new(),
+        assigned: HashSet::new(), warnings: vec![]
+    };
+    for node in program {
+        if let NodeKind::Label(ref name) = node.kind {
+            linter.labels.insert(name, node.span);
+        }
+        checker::find_assigned(node, &mut linter.assigned);
+    }
+
+    let (mut reachable, mut warned) = (true, false);
+    for node in program {
+        if let NodeKind::Label(_) = node.kind {
+            reachable = true;
+            linter.forget();
+            continue;
+        }
+        // one warning for each stretch of unreachable code
+        if !reachable && !warned {
+            linter.warn("W005", node.span,
+                        "unreachable code after return".to_string());
+            warned = true;
+        }
+        linter.visit(node);
+        if is_return(node) { reachable = false; warned = false; }
+    }
+
+    for (i, node) in program.iter().enumerate() {
+        if let NodeKind::Label(ref name) = node.kind {
+            if !linter.used.contains(&name[..]) {
+                linter.warn("W001", node.span,
+                            format!("label {} is never used", name));
+            }
+            if let Some(&op) = linter.callbacks.get(&name[..]) {
+                // a callback's body runs up to its first return, falling
+                // through any labels in the way
+                let mut body = program[i + 1..].iter()
+                    .take_while(|n| !is_return(n));
+                if !body.any(sets_underscore) {
+                    linter.warn("W004", node.span, format!(
+                        "label {} is called back to by {} but never sets _",
+                        name, op));
+                }
+            }
+        }
+    }
+
+    let mut warnings = linter.warnings;
+    warnings.sort_by_key(|w| w.span.offset);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use macaroni::Macaroni;
+
+    fn codes(src: &str) -> Vec<&'static str> {
+        Macaroni::new().lint(src).unwrap().iter().map(|w| w.code).collect()
+    }
+
+    #[test]
+    fn clean() {
+        assert!(codes("set x 1 print tobase x 10").is_empty());
+        assert!(codes("map [1] f return label f set _ 2 return").is_empty());
+    }
+
+    #[test]
+    fn unused_label() {
+        assert_eq!(codes("return label a return"), ["W001"]);
+    }
+
+    #[test]
+    fn unknown_label() {
+        assert_eq!(codes("goto nowhere"), ["W002"]);
+    }
+
+    #[test]
+    fn read_before_set() {
+        assert_eq!(codes("print x set x \"hi\""), ["W003"]);
+        // `_` is set by whoever calls back
+        assert!(codes("map [1] f return label f set _ add _ 1 return")
+            .is_empty());
+        // a jump might set it
+        assert!(codes("goto init print x return label init set x \"hi\" return")
+            .is_empty());
+    }
+
+    #[test]
+    fn callback_without_underscore() {
+        assert_eq!(codes("map [1] f return label f return"), ["W004"]);
+        assert_eq!(codes("sort [2 1] f return label f return"), ["W004"]);
+        // leaving `_` alone is how `index` picks what's truthy
+        assert!(codes("index [0 1] f return label f return").is_empty());
+    }
+
+    #[test]
+    fn unreachable() {
+        assert_eq!(codes("return print \"never\" print \"nor this\""),
+                   ["W005"]);
+    }
+}
@@ -4,13 +4,29 @@ use macaroni_lang::*;
 extern crate getopts;
 use getopts::Options;
 
+extern crate rustyline;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+mod debugger;
+mod tracer;
+
 use std::io;
 use std::io::prelude::*;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+/// Roughly how much stack each level of a nested expression takes, with
+/// room to spare.
+const FRAME_SIZE: usize = 8 * 1024;
 
 fn main() {
-    let mut mac = macaroni::Macaroni::new();
+    let mac = macaroni::Macaroni::new();
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
@@ -18,6 +34,25 @@ fn main() {
     opts.optflag("h", "help", "output this usage information");
     opts.optflag("v", "version", "output the current Macaroni version");
     opts.optflag("i", "interactive", "start an interactive REPL");
+    opts.optflag("p", "print-result",
+        "print the value of the program's last statement");
+    opts.optflag("r", "raw",
+        "show arrays as numbers, even if they look like strings");
+    opts.optflag("", "list-ops",
+        "list every operator in Markdown, as in the README");
+    opts.optflag("c", "check",
+        "look for arguments of the wrong kind before running");
+    opts.optflag("d", "debug", "step through the program in a debugger");
+    opts.optflag("t", "trace", "log every step the program takes to stderr");
+    opts.optopt("", "max-steps", "stop after applying this many operators",
+        "N");
+    opts.optopt("", "max-time", "stop after running for this many seconds",
+        "SECS");
+    opts.optopt("", "max-depth", &format!(
+        "stop if callbacks nest this deep (default {})",
+        macaroni::DEFAULT_DEPTH), "N");
+    opts.optopt("", "max-memory",
+        "stop if values take up roughly this many bytes", "BYTES");
     opts.optopt("e", "evaluate", "takes one parameter, runs as Macaroni code",
         "[code]");
     let matches = match opts.parse(&args[1..]) {
@@ -28,48 +63,358 @@ fn main() {
         }
     };
 
+    let limits = match limits(&matches) {
+        Some(limits) => limits,
+        None => {
+            usage(&program, opts);
+            return;
+        }
+    };
+
     if matches.opt_present("h") {
         usage(&program, opts);
     } else if matches.opt_present("v") {
         println!("version 0.0.2 (alpha)");
-    } else if matches.opt_present("i") {
-        loop {
-            print!(">>> ");
-            io::stdout().flush().unwrap();
-            let mut line = String::new();
-            if io::stdin().read_line(&mut line).unwrap() == 0 { break; }
-
-            println!(" => {:?}", mac.run(line));
+    } else if matches.opt_present("list-ops") {
+        for op in mac.ops() {
+            let sig = op.signature();
+            let (args, ret) = sig.split_once(" -> ").unwrap();
+            println!("- `{}` (`{}` -> `{}`): {}", op.name, args, ret, op.help);
         }
-    } else if matches.opt_present("e") {
-        mac.run(matches.opt_str("e").unwrap());
+    } else if matches.free.first().map(|s| &s[..]) == Some("lint") {
+        if !lint(&mac, &matches.free[1..]) { process::exit(1); }
+    } else if matches.free.first().map(|s| &s[..]) == Some("fmt") {
+        if !fmt(&mac, &matches.free[1..]) { process::exit(1); }
     } else {
-        match matches.free.len() {
+        // every callback can hold expressions as deep as the parser allows,
+        // and overflowing the stack can't be recovered from
+        let depth = limits.depth.unwrap_or(macaroni::DEFAULT_DEPTH);
+        let stack = (depth + 1).saturating_mul(parser::MAX_DEPTH + 1)
+            .saturating_mul(FRAME_SIZE);
+        let thread = thread::Builder::new().stack_size(stack)
+            .spawn(move || execute(matches, opts, &program, limits));
+        match thread {
+            Ok(thread) => if thread.join().is_err() { process::exit(101); },
+            Err(_) => {
+                eprintln!("not enough memory for callbacks nested {} deep",
+                          depth);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Runs the REPL or a program, as `matches` says, with `limits`.
+fn execute(matches: getopts::Matches, opts: Options, program: &str,
+           limits: macaroni::Limits) {
+    let mut mac = macaroni::Macaroni::new();
+    mac.set_limits(limits);
+    if matches.opt_present("i") {
+        repl(&mut mac, matches.opt_present("r"));
+    } else {
+        let check = matches.opt_present("c");
+        let mode = if matches.opt_present("d") { Mode::Debug }
+            else if matches.opt_present("t") { Mode::Trace }
+            else { Mode::Run };
+        let result = if matches.opt_present("e") {
+            run(&mut mac, "<eval>", matches.opt_str("e").unwrap(), check,
+                mode)
+        } else { match matches.free.len() {
             0 => {
                 let mut code = String::new();
                 io::stdin().read_to_string(&mut code).unwrap();
-                mac.run(code);
+                run(&mut mac, "<stdin>", code, check, mode)
             },
             1 => {
                 let mut code = String::new();
                 match File::open(matches.free[0].clone()) {
                     Ok(mut f) => {
                         match f.read_to_string(&mut code) {
-                            Ok(_) => { mac.run(code); },
-                            Err(_) => file_err(&matches.free[0])
+                            Ok(_) => run(&mut mac, &matches.free[0], code,
+                                         check, mode),
+                            Err(_) => { file_err(&matches.free[0]); None }
                         }
                     },
-                    Err(_) => file_err(&matches.free[0])
+                    Err(_) => { file_err(&matches.free[0]); None }
                 }
             },
-            _ => usage(&program, opts)
+            _ => {
+                usage(program, opts);
+                return;
+            }
+        } };
+        if let Some(val) = result {
+            if matches.opt_present("p") {
+                println!("{}", show(&val, matches.opt_present("r")));
+            }
+        }
+    }
+}
+
+/// Reads the `--max-*` options, or returns None if one doesn't parse.
+fn limits(matches: &getopts::Matches) -> Option<macaroni::Limits> {
+    let time = match matches.opt_get::<f64>("max-time") {
+        Ok(Some(secs)) => Some(Duration::try_from_secs_f64(secs).ok()?),
+        Ok(None) => None,
+        Err(_) => return None
+    };
+    Some(macaroni::Limits {
+        steps: matches.opt_get("max-steps").ok()?,
+        time,
+        depth: matches.opt_get("max-depth").ok()?
+            .or(Some(macaroni::DEFAULT_DEPTH)),
+        memory: matches.opt_get("max-memory").ok()?
+    })
+}
+
+/// Lints each file in `names`, or stdin if there aren't any, returning
+/// whether they were all clean.
+fn lint(mac: &macaroni::Macaroni, names: &[String]) -> bool {
+    let stdin = ["<stdin>".to_string()];
+    let mut clean = true;
+    for name in if names.is_empty() { &stdin[..] } else { names } {
+        let mut code = String::new();
+        let read = if names.is_empty() {
+            io::stdin().read_to_string(&mut code)
+        } else {
+            File::open(name).and_then(|mut f| f.read_to_string(&mut code))
+        };
+        if read.is_err() {
+            file_err(name);
+            clean = false;
+            continue;
+        }
+        match mac.lint(&code) {
+            Ok(warnings) => for w in warnings {
+                println!("{}", w.render(name, &code));
+                clean = false;
+            },
+            Err(e) => {
+                eprintln!("{}", e.render(name, &code));
+                clean = false;
+            }
+        }
+    }
+    clean
+}
+
+/// Formats each file in `names` in place, or stdin to stdout if there
+/// aren't any, returning whether that all worked.
+fn fmt(mac: &macaroni::Macaroni, names: &[String]) -> bool {
+    if names.is_empty() {
+        let mut code = String::new();
+        if io::stdin().read_to_string(&mut code).is_err() {
+            file_err("<stdin>");
+            return false;
+        }
+        return match mac.format(&code) {
+            Ok(formatted) => { print!("{}", formatted); true },
+            Err(e) => { eprintln!("{}", e.render("<stdin>", &code)); false }
+        };
+    }
+    let mut ok = true;
+    for name in names {
+        let code = match fs::read_to_string(name) {
+            Ok(code) => code,
+            Err(_) => { file_err(name); ok = false; continue; }
+        };
+        match mac.format(&code) {
+            Ok(ref formatted) if *formatted == code => (),
+            Ok(formatted) => if fs::write(name, formatted).is_err() {
+                println!("could not write file {}", name);
+                ok = false;
+            },
+            Err(e) => {
+                eprintln!("{}", e.render(name, &code));
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+fn repl(mac: &mut macaroni::Macaroni, mut raw: bool) {
+    let mut rl = match DefaultEditor::new() {
+        Ok(rl) => rl,
+        Err(e) => {
+            eprintln!("could not start the REPL: {}", e);
+            process::exit(1);
+        }
+    };
+    let history = history_path();
+    if let Some(ref path) = history { let _ = rl.load_history(path); }
+
+    let mut code = String::new();
+    loop {
+        let prompt = if code.is_empty() { ">>> " } else { "... " };
+        match rl.readline(prompt) {
+            Ok(ref line) if code.is_empty() && line.starts_with(':') => {
+                let _ = rl.add_history_entry(&line[..]);
+                if !command(mac, &mut raw, line) { break; }
+                continue;
+            },
+            Ok(line) => {
+                code.push_str(&line);
+                code.push('\n');
+            },
+            // ^C throws away whatever has been typed so far
+            Err(ReadlineError::Interrupted) => { code.clear(); continue; },
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        }
+
+        // keep reading while an operator is still waiting for arguments
+        match mac.parse(&code) {
+            Err(ref e) if e.is_incomplete() => continue,
+            _ => ()
         }
+        let _ = rl.add_history_entry(code.trim_end());
+        match guarded_run(mac, &code) {
+            Some(Ok(Some(val))) => println!(" => {}", show(&val, raw)),
+            Some(Ok(None)) => println!(" => nothing"),
+            Some(Err(e)) => println!("{}", render(mac, &e, "<repl>", &code)),
+            None => ()
+        }
+        code.clear();
+    }
+
+    if let Some(ref path) = history { let _ = rl.save_history(path); }
+}
+
+/// Runs a REPL `:command`, returning false if it's time to quit.
+fn command(mac: &mut macaroni::Macaroni, raw: &mut bool, line: &str) -> bool {
+    let (cmd, arg) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, "")
+    };
+    match cmd {
+        ":help" if !arg.is_empty() => match mac.op(arg) {
+            Some(op) => println!("{} {}: {}", op.name, op.signature(), op.help),
+            None => println!("no operator called {}", arg)
+        },
+        ":help" => {
+            println!(":help <op>     describe an operator");
+            println!(":vars          list variables and their values");
+            println!(":labels        list labels defined so far");
+            println!(":reset         forget all variables and labels");
+            println!(":load <file>   run a file in this session");
+            println!(":type <expr>   show an expression's type, without running it");
+            println!(":raw           toggle showing strings as arrays of numbers");
+            println!(":quit          leave the REPL");
+            println!();
+            println!("operators (n number, a array, l label, v variable, \
+                      * anything, - nothing):");
+            for op in mac.ops() {
+                println!("  {:<10} {}", op.name, op.signature());
+            }
+        },
+        ":vars" => {
+            let vars = mac.vars();
+            let mut names = vars.keys().collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                println!("{} = {}", name, show(&vars[name], *raw));
+            }
+        },
+        ":labels" => {
+            for label in mac.labels() { println!("{}", label); }
+        },
+        ":reset" => mac.reset(),
+        ":raw" => *raw = !*raw,
+        ":load" => {
+            let mut code = String::new();
+            match File::open(arg).and_then(|mut f| f.read_to_string(&mut code)) {
+                Ok(_) => if let Some(Err(e)) = guarded_run(mac, &code) {
+                    println!("{}", render(mac, &e, arg, &code));
+                },
+                Err(_) => file_err(arg)
+            }
+        },
+        ":type" => match mac.infer(arg) {
+            Ok((_, ref errors)) if !errors.is_empty() => for e in errors {
+                println!("{}", e.render("<repl>", arg));
+            },
+            Ok((kind, _)) => println!("{}", match kind {
+                macaroni::Kind::Num => "Num",
+                macaroni::Kind::Arr => "Arr",
+                macaroni::Kind::Any => "Num or Arr",
+                _ => "nothing"
+            }),
+            Err(e) => println!("{}", e.render("<repl>", arg))
+        },
+        ":quit" | ":q" => return false,
+        _ => println!("unknown command {} (try :help)", cmd)
     }
+    true
+}
+
+/// Runs `code` in the REPL, or returns None (having said so) if the
+/// interpreter panicked; a bug in it shouldn't lose everything set so far.
+fn guarded_run(mac: &mut macaroni::Macaroni, code: &str)
+        -> Option<Result<Option<macaroni::Val>, macaroni::MacaroniError>> {
+    let result = panic::catch_unwind(AssertUnwindSafe(||
+        mac.run(code.to_string())));
+    if result.is_err() {
+        println!("internal error; variables have been kept");
+    }
+    result.ok()
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".macaroni_history"))
+}
+
+#[derive(Clone, Copy)]
+enum Mode { Run, Debug, Trace }
+
+fn run(mac: &mut macaroni::Macaroni, name: &str, code: String, check: bool,
+       mode: Mode) -> Option<macaroni::Val> {
+    if check {
+        // parse errors are reported by `run` below
+        if let Ok(errors) = mac.check(&code) {
+            for e in &errors { eprintln!("{}", e.render(name, &code)); }
+            if !errors.is_empty() { process::exit(1); }
+        }
+    }
+    match mode {
+        Mode::Run => (),
+        Mode::Debug => match debugger::Debugger::new(name, &code) {
+            Ok(debugger) => mac.set_hook(debugger),
+            Err(e) => {
+                eprintln!("could not start the debugger: {}", e);
+                process::exit(1);
+            }
+        },
+        Mode::Trace => mac.set_hook(tracer::Tracer::new(name))
+    }
+    match mac.run(code.clone()) {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("{}", render(mac, &e, name, &code));
+            process::exit(1);
+        }
+    }
+}
+
+/// Renders an error from running `code`, which might be in code that was
+/// run earlier if it happened in a callback or after a `goto`.
+fn render(mac: &macaroni::Macaroni, e: &macaroni::MacaroniError, name: &str,
+          code: &str) -> String {
+    e.render(name, mac.source(e.span()).unwrap_or(code))
+}
+
+fn show(val: &macaroni::Val, raw: bool) -> String {
+    if raw { format!("{:?}", val) } else { format!("{}", val) }
 }
 
 fn usage(program: &str, opts: Options) {
-    print!("{}", opts.usage(&format!("Usage: {} [filename] [options...]",
-        program)));
+    print!("{}", opts.usage(&format!("Usage: {0} [filename] [options...]
+       {0} lint [filename...]
+       {0} fmt [filename...]", program)));
 }
 
 fn file_err(name: &str) {
@@ -0,0 +1,173 @@
+//! Turns lexemes into a tree of statements. Since every operator has a fixed
+//! arity, nesting is decided entirely by looking up how many arguments each
+//! operator wants; the caller supplies that lookup so the parser doesn't need
+//! to know which operators exist.
+
+use lexer::{Lexeme, LexemeKind, Span};
+use macaroni::{Macaroni, MacaroniError, Val};
+
+#[derive(Clone, Debug)]
+pub enum NodeKind {
+    Call { op: String, args: Vec<Node> },
+    Literal(Val),
+    VarRef(String),
+    /// `label name`; only ever appears as a top-level statement.
+    Label(String)
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span
+}
+
+/// Marks `node`, and everything in it, as being from chunk `chunk`.
+pub fn set_chunk(node: &mut Node, chunk: usize) {
+    node.span.chunk = chunk;
+    if let NodeKind::Call { ref mut args, .. } = node.kind {
+        for arg in args { set_chunk(arg, chunk); }
+    }
+}
+
+/// How deeply calls and array literals may nest. Everything that walks the
+/// tree, the interpreter included, recurses, so without a limit a long
+/// enough chain of `wrap`s or `[`s would overflow the stack.
+pub const MAX_DEPTH: usize = 256;
+
+struct Parser<'a, F: Fn(&str) -> Option<usize>> {
+    lexemes: Vec<&'a Lexeme>,
+    i: usize,
+    arity: F,
+    /// How many calls and arrays the parser is inside.
+    depth: usize
+}
+
+impl<'a, F: Fn(&str) -> Option<usize>> Parser<'a, F> {
+    fn statement(&mut self) -> Result<Node, MacaroniError> {
+        let lexeme = self.lexemes[self.i];
+        match lexeme.kind {
+            LexemeKind::Ident(ref name) if name == "label" => {
+                self.i += 1;
+                match self.lexemes.get(self.i).map(|l| &l.kind) {
+                    Some(LexemeKind::Ident(name))
+                            if (self.arity)(name).is_none() &&
+                               name != "label" => {
+                        self.i += 1;
+                        Ok(Node {
+                            kind: NodeKind::Label(name.clone()),
+                            span: lexeme.span
+                        })
+                    },
+                    Some(_) => Err(MacaroniError::BadLabel {
+                        span: self.lexemes[self.i].span
+                    }),
+                    None => Err(MacaroniError::MissingArgument {
+                        span: lexeme.span
+                    })
+                }
+            },
+            _ => self.expr()
+        }
+    }
+
+    /// Goes one level deeper for the call or array at `span`, failing if
+    /// that's too deep.
+    fn enter(&mut self, span: Span) -> Result<(), MacaroniError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(MacaroniError::TooDeep { span });
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Node, MacaroniError> {
+        let lexeme = self.lexemes[self.i];
+        self.i += 1;
+        let kind = match lexeme.kind {
+            LexemeKind::Number(n) => NodeKind::Literal(Val::Num(n)),
+            LexemeKind::Str(ref s) =>
+                NodeKind::Literal(Val::Arr(Macaroni::string_to_arr(s))),
+            LexemeKind::Ident(ref name) => match (self.arity)(name) {
+                Some(arity) => {
+                    self.enter(lexeme.span)?;
+                    let mut args = Vec::with_capacity(arity);
+                    while args.len() < arity {
+                        match self.lexemes.get(self.i).map(|l| &l.kind) {
+                            Some(LexemeKind::Ident(arg))
+                                    if arg == "label" => return Err(
+                                MacaroniError::LabelArgument {
+                                    span: self.lexemes[self.i].span
+                                }),
+                            Some(_) => args.push(self.expr()?),
+                            None => return Err(MacaroniError::MissingArgument {
+                                span: lexeme.span
+                            })
+                        }
+                    }
+                    self.depth -= 1;
+                    NodeKind::Call { op: name.clone(), args }
+                },
+                None => NodeKind::VarRef(name.clone())
+            },
+            LexemeKind::Open => {
+                let (val, span) = self.array(lexeme.span)?;
+                return Ok(Node { kind: NodeKind::Literal(val), span });
+            },
+            LexemeKind::Close => return Err(MacaroniError::UnmatchedBracket {
+                span: lexeme.span
+            }),
+            LexemeKind::Comment(_) | LexemeKind::Whitespace => unreachable!()
+        };
+        Ok(Node { kind, span: lexeme.span })
+    }
+
+    /// Parses the rest of an array literal whose `[` is at `open`, returning
+    /// it along with a span covering the whole thing.
+    fn array(&mut self, open: Span) -> Result<(Val, Span), MacaroniError> {
+        self.enter(open)?;
+        let mut arr = vec![];
+        loop {
+            let lexeme = match self.lexemes.get(self.i) {
+                Some(lexeme) => *lexeme,
+                None => return Err(MacaroniError::UnterminatedArray {
+                    span: open
+                })
+            };
+            self.i += 1;
+            arr.push(match lexeme.kind {
+                LexemeKind::Number(n) => Val::Num(n),
+                LexemeKind::Str(ref s) => Val::Arr(Macaroni::string_to_arr(s)),
+                LexemeKind::Open => self.array(lexeme.span)?.0,
+                LexemeKind::Close => {
+                    let span = Span {
+                        len: lexeme.span.offset + lexeme.span.len - open.offset,
+                        ..open
+                    };
+                    self.depth -= 1;
+                    return Ok((Val::Arr(arr), span));
+                },
+                _ => return Err(MacaroniError::BadArrayElement {
+                    span: lexeme.span
+                })
+            });
+        }
+    }
+}
+
+/// Parses a whole program into its top-level statements. `arity` gives the
+/// number of arguments an operator takes, or `None` if the name isn't one.
+pub fn parse<F>(lexemes: &[Lexeme], arity: F)
+        -> Result<Vec<Node>, MacaroniError>
+        where F: Fn(&str) -> Option<usize> {
+    let mut parser = Parser {
+        lexemes: lexemes.iter().filter(|l| !l.is_trivia()).collect(),
+        i: 0,
+        arity,
+        depth: 0
+    };
+    let mut statements = vec![];
+    while parser.i < parser.lexemes.len() {
+        statements.push(parser.statement()?);
+    }
+    Ok(statements)
+}
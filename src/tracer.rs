@@ -0,0 +1,47 @@
+//! `--trace` mode: logs every step the program takes to stderr, one line
+//! each, indented by how deeply callbacks are nested.
+
+use macaroni_lang::macaroni::{Arg, Hook, Macaroni, Span, Val};
+
+pub struct Tracer {
+    name: String
+}
+
+impl Tracer {
+    pub fn new(name: &str) -> Tracer {
+        Tracer { name: name.to_string() }
+    }
+
+    fn log(&self, mac: &Macaroni, span: Span, msg: String) {
+        let depth = mac.states().len().saturating_sub(1);
+        eprintln!("{}:{}:{}: {}{}", self.name, span.line, span.col,
+                  "  ".repeat(depth), msg);
+    }
+}
+
+impl Hook for Tracer {
+    fn after_op(&mut self, mac: &Macaroni, op: &str, args: &[Arg],
+                result: Option<&Val>, span: Span) {
+        // jumps get their own, more useful, lines
+        if op == "goto" || op == "return" { return; }
+        let args = args.iter().map(|a| format!(" {}", a)).collect::<String>();
+        let result = result.map_or("nothing".to_string(), |r| r.to_string());
+        self.log(mac, span, format!("{}{} => {}", op, args, result));
+    }
+
+    fn on_goto(&mut self, mac: &Macaroni, name: &str, span: Span, to: Span) {
+        self.log(mac, span, format!("goto {} -> {}:{}", name, to.line, to.col));
+    }
+
+    fn on_return(&mut self, mac: &Macaroni, span: Span, to: Option<Span>) {
+        self.log(mac, span, match to {
+            Some(to) => format!("return -> {}:{}", to.line, to.col),
+            None => "return -> end".to_string()
+        });
+    }
+
+    fn on_callback(&mut self, mac: &Macaroni, op: &str, name: &str, arg: &Val,
+                   span: Span) {
+        self.log(mac, span, format!("{} calls {} with _ = {}", op, name, arg));
+    }
+}